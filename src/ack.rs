@@ -0,0 +1,62 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+type Callback = Box<dyn FnOnce() + Send + Sync>;
+
+struct Pending {
+    /// Set by copies dropped without getting emitted
+    failed: AtomicBool,
+    on_emitted: Option<Callback>,
+}
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        if !*self.failed.get_mut() {
+            if let Some(on_emitted) = self.on_emitted.take() {
+                on_emitted();
+            }
+        }
+    }
+}
+
+/// Acknowledgement of a queued message, running its callback once all copies of the message
+/// got emitted. Copies dropped unacknowledged, like those of full queues or left over on
+/// shutdown, cancel the callback.
+pub struct Ack {
+    pending: Arc<Pending>,
+    acknowledged: bool,
+}
+
+impl Ack {
+    pub fn new(on_emitted: impl FnOnce() + Send + Sync + 'static) -> Self {
+        Self {
+            pending: Arc::new(Pending {
+                failed: AtomicBool::new(false),
+                on_emitted: Some(Box::new(on_emitted)),
+            }),
+            acknowledged: false,
+        }
+    }
+
+    /// Acknowledges the emission of this copy of the message
+    pub fn emitted(mut self) {
+        self.acknowledged = true;
+    }
+}
+
+impl Clone for Ack {
+    fn clone(&self) -> Self {
+        Self {
+            pending: self.pending.clone(),
+            acknowledged: false,
+        }
+    }
+}
+
+impl Drop for Ack {
+    fn drop(&mut self) {
+        if !self.acknowledged {
+            self.pending.failed.store(true, Ordering::Relaxed);
+        }
+    }
+}
@@ -0,0 +1,205 @@
+use crate::container_logs;
+use crate::events::event_time;
+use crate::router::{self, Router};
+use crate::runtime::{Endpoint, Runtime};
+use crate::syslog::{Facility, Severity, StructuredData};
+use crate::{EmitterData, ONE_SECOND};
+use bollard::models::{EventMessage, EventMessageTypeEnum};
+use bollard::system::EventsOptions;
+use chrono::{DateTime, Utc};
+use futures_util::StreamExt;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+use std::sync::Arc;
+use tokio_util::sync::CancellationToken;
+
+/// Name of the structured data element describing the audited event
+const AUDIT_SD_NAME: &str = "audit";
+
+/// Daemon-wide events forwarded as audit log, configured by the `AUDIT_EVENTS` environment variable
+#[derive(Clone)]
+pub struct Audit {
+    /// Event types with an optional action, all actions if `None`
+    rules: Vec<(EventMessageTypeEnum, Option<String>)>,
+}
+
+impl Audit {
+    pub fn from_env() -> Result<Option<Self>, String> {
+        let Ok(value) = std::env::var("AUDIT_EVENTS") else {
+            return Ok(None);
+        };
+        let rules = value
+            .split(',')
+            .map(str::trim)
+            .filter(|rule| !rule.is_empty())
+            .map(|rule| {
+                let (typ, action) = rule
+                    .split_once(':')
+                    .map_or((rule, None), |(typ, action)| (typ, Some(action.trim())));
+                let typ = typ
+                    .trim()
+                    .parse()
+                    .map_err(|_| format!("Unknown audit event type \"{typ}\""))?;
+                Ok((typ, action.map(String::from)))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+
+        Ok((!rules.is_empty()).then_some(Self { rules }))
+    }
+
+    fn types(&self) -> Vec<String> {
+        let mut types: Vec<String> = self.rules.iter().map(|(typ, _)| typ.to_string()).collect();
+        types.sort_unstable();
+        types.dedup();
+        types
+    }
+
+    fn is_audited(&self, typ: EventMessageTypeEnum, action: &str) -> bool {
+        // Actions like "exec_start: sh" carry details after the colon
+        let action = action.split_once(':').map_or(action, |(action, _)| action);
+        self.rules
+            .iter()
+            .any(|(t, a)| *t == typ && a.as_deref().is_none_or(|a| a == action))
+    }
+}
+
+fn format(
+    event: &EventMessage,
+    typ: EventMessageTypeEnum,
+    action: &str,
+    hostname: &str,
+) -> Vec<u8> {
+    let empty = HashMap::new();
+    let (id, attributes) = event.actor.as_ref().map_or((None, &empty), |actor| {
+        (
+            actor.id.as_deref(),
+            actor.attributes.as_ref().unwrap_or(&empty),
+        )
+    });
+    let mut attributes: Vec<_> = attributes.iter().collect();
+    attributes.sort_unstable();
+
+    let typ = typ.to_string();
+    // Actions like "exec_start: sh -c …" carry details after the colon, unfit for the MSGID
+    let base_action = action.split_once(':').map_or(action, |(action, _)| action);
+    let formatter = container_logs::get_formatter(
+        Facility::LogAudit,
+        hostname,
+        None,
+        Some(&format!("{typ}.{base_action}")),
+    );
+
+    let mut msg = format!("{typ} {action}");
+    if let Some(id) = id {
+        let _ = write!(msg, " {id}");
+    }
+    let mut structured_data = StructuredData::default();
+    if formatter.supports_structured_data() {
+        structured_data.add(
+            &container_logs::sd_id(AUDIT_SD_NAME),
+            [("type", typ.as_str()), ("action", action)]
+                .into_iter()
+                .chain(id.map(|id| ("id", id)))
+                .chain(attributes.iter().map(|(k, v)| (k.as_str(), v.as_str()))),
+        );
+    } else {
+        for (key, value) in attributes {
+            let _ = write!(msg, " {key}=\"{value}\"");
+        }
+    }
+
+    formatter.format(
+        msg.as_bytes(),
+        Some("dockerd"),
+        Severity::Notice,
+        &event_time(event),
+        &structured_data,
+    )
+}
+
+/// Forwards the audited events of the endpoint to the default destinations until cancelled,
+/// resuming after reconnects from the last forwarded event
+pub async fn forward(
+    audit: Audit,
+    router: Arc<Router>,
+    endpoint: Arc<Endpoint>,
+    cancellation_token: CancellationToken,
+) {
+    tracing::trace!("audit::forward(endpoint = \"{}\") start", endpoint.name);
+    let mut filters = HashMap::with_capacity(1);
+    filters.insert("type".to_string(), audit.types());
+    let mut since: Option<DateTime<Utc>> = None;
+    // Actor IDs and actions of the events forwarded at `since`, which get replayed on reconnect
+    let mut forwarded: HashSet<(Option<String>, Option<String>)> = HashSet::new();
+
+    'outer_loop: loop {
+        let docker = match Runtime::connect(&endpoint).await {
+            Ok(runtime) => runtime.docker,
+            Err(err) => {
+                tracing::warn!(
+                    "Could not connect to Docker daemon \"{}\" for audit events: {err}",
+                    endpoint.name
+                );
+                tokio::select! {
+                    () = cancellation_token.cancelled() => break,
+                    () = tokio::time::sleep(ONE_SECOND) => continue,
+                }
+            }
+        };
+        let events = &mut docker.events(Some(EventsOptions {
+            since: since.map(|ts| format!("{}.{:09}", ts.timestamp(), ts.timestamp_subsec_nanos())),
+            until: None,
+            filters: filters.clone(),
+        }));
+
+        loop {
+            tokio::select! {
+                biased;
+                () = cancellation_token.cancelled() => break 'outer_loop,
+                event = events.next() => {
+                    match event {
+                        Some(Ok(event)) => {
+                            let ts = event_time(&event);
+                            let key = (
+                                event.actor.as_ref().and_then(|actor| actor.id.clone()),
+                                event.action.clone(),
+                            );
+                            match since {
+                                Some(since) if ts < since => continue,
+                                Some(since) if ts == since => {
+                                    if !forwarded.insert(key) {
+                                        continue;
+                                    }
+                                }
+                                _ => {
+                                    since = Some(ts);
+                                    forwarded.clear();
+                                    forwarded.insert(key);
+                                }
+                            }
+                            let (Some(typ), Some(action)) = (event.typ, event.action.as_deref()) else {
+                                continue;
+                            };
+                            if !audit.is_audited(typ, action) {
+                                continue;
+                            }
+
+                            let data = format(&event, typ, action, &endpoint.hostname);
+                            router::send(&router.default_route(), EmitterData::new(data, None)).await;
+                        },
+                        Some(Err(err)) => {
+                            tracing::warn!("Error while reading audit event stream: {err}");
+                            break;
+                        },
+                        None => break,
+                    }
+                },
+            }
+        }
+        tokio::select! {
+            () = cancellation_token.cancelled() => break,
+            () = tokio::time::sleep(ONE_SECOND) => {},
+        }
+    }
+    tracing::trace!("audit::forward(endpoint = \"{}\") end", endpoint.name);
+}
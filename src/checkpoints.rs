@@ -0,0 +1,180 @@
+use crate::ack::Ack;
+use chrono::{DateTime, SecondsFormat, Utc};
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio_util::sync::CancellationToken;
+
+const SAVE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Checkpoint key of a container, prefixed by the endpoint scope unless empty
+fn key(scope: &str, container_id: &str) -> String {
+    if scope.is_empty() {
+        container_id.to_string()
+    } else {
+        format!("{scope}/{container_id}")
+    }
+}
+
+/// Timestamps of the last emitted log line per container, persisted to the `CHECKPOINT_FILE`
+pub struct Checkpoints {
+    path: Option<PathBuf>,
+    since: Mutex<HashMap<String, DateTime<Utc>>>,
+    dirty: AtomicBool,
+}
+
+impl Checkpoints {
+    pub fn from_env() -> Self {
+        let path = std::env::var_os("CHECKPOINT_FILE").map(PathBuf::from);
+        let since =
+            path.as_ref()
+                .map_or_else(HashMap::new, |path| match std::fs::read_to_string(path) {
+                    Ok(content) => content
+                        .lines()
+                        .filter_map(|line| {
+                            let (container_id, since) = line.split_once(' ')?;
+                            let since = DateTime::parse_from_rfc3339(since).ok()?;
+                            Some((container_id.to_string(), since.to_utc()))
+                        })
+                        .collect(),
+                    Err(err) if err.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+                    Err(err) => {
+                        tracing::warn!("Could not read checkpoint file: {err}");
+                        HashMap::new()
+                    }
+                });
+
+        Self {
+            path,
+            since: Mutex::new(since),
+            dirty: AtomicBool::new(false),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, DateTime<Utc>>> {
+        self.since.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn get(&self, scope: &str, container_id: &str) -> Option<DateTime<Utc>> {
+        self.lock().get(&key(scope, container_id)).copied()
+    }
+
+    /// Advances the checkpoint of the container, never moving it backwards as the
+    /// acknowledgements of multiple destinations may arrive out of order
+    pub fn set(&self, scope: &str, container_id: &str, since: DateTime<Utc>) {
+        if self.path.is_none() {
+            return;
+        }
+        let changed = match self.lock().entry(key(scope, container_id)) {
+            Entry::Occupied(mut entry) if *entry.get() < since => {
+                entry.insert(since);
+                true
+            }
+            Entry::Occupied(_) => false,
+            Entry::Vacant(entry) => {
+                entry.insert(since);
+                true
+            }
+        };
+        if changed {
+            self.dirty.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Acknowledgement advancing the checkpoint of the container once the log record got
+    /// emitted, `None` without checkpoint file
+    pub fn ack(
+        self: &Arc<Self>,
+        scope: &str,
+        container_id: &str,
+        since: DateTime<Utc>,
+    ) -> Option<Ack> {
+        self.path.as_ref()?;
+        let checkpoints = self.clone();
+        let (scope, container_id) = (scope.to_string(), container_id.to_string());
+        Some(Ack::new(move || {
+            checkpoints.set(&scope, &container_id, since);
+        }))
+    }
+
+    /// Drops the checkpoint of a removed container
+    pub fn remove(&self, scope: &str, container_id: &str) {
+        if self.lock().remove(&key(scope, container_id)).is_some() {
+            tracing::debug!("Removed checkpoint of container \"{container_id}\"");
+            self.dirty.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Drops checkpoints of the scope whose containers are not contained in the given list of
+    /// existing containers
+    pub fn retain(&self, scope: &str, container_ids: &HashSet<String>) {
+        let prefix = key(scope, "");
+        let removed = {
+            let mut checkpoints = self.lock();
+            let count = checkpoints.len();
+            checkpoints.retain(|key, _| {
+                key.strip_prefix(&prefix)
+                    .is_none_or(|container_id| container_ids.contains(container_id))
+            });
+            count - checkpoints.len()
+        };
+        if removed > 0 {
+            tracing::debug!("Removed {removed} checkpoints of vanished containers");
+            self.dirty.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Atomically replaces the checkpoint file if any checkpoint changed since the last save
+    pub async fn save(&self) {
+        let Some(path) = self.path.as_ref() else {
+            return;
+        };
+        if !self.dirty.swap(false, Ordering::Relaxed) {
+            return;
+        }
+
+        let content =
+            self.lock()
+                .iter()
+                .fold(String::new(), |mut content, (container_id, since)| {
+                    let since = since.to_rfc3339_opts(SecondsFormat::Nanos, true);
+                    let _ = writeln!(content, "{container_id} {since}");
+                    content
+                });
+
+        let mut tmp_path = path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+        let result = async {
+            let mut file = tokio::fs::File::create(&tmp_path).await?;
+            file.write_all(content.as_bytes()).await?;
+            file.sync_all().await?;
+            tokio::fs::rename(&tmp_path, path).await
+        }
+        .await;
+
+        if let Err(err) = result {
+            tracing::warn!("Could not save checkpoint file: {err}");
+            self.dirty.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Periodically saves the checkpoints until cancelled
+pub async fn persist(checkpoints: Arc<Checkpoints>, cancellation_token: CancellationToken) {
+    tracing::trace!("persist() start");
+    loop {
+        tokio::select! {
+            biased;
+            () = cancellation_token.cancelled() => break,
+            () = tokio::time::sleep(SAVE_INTERVAL) => checkpoints.save().await,
+        }
+    }
+    checkpoints.save().await;
+    tracing::trace!("persist() end");
+}
@@ -1,20 +1,34 @@
-use crate::syslog::{Facility, Formatter, Severity};
+use crate::checkpoints::Checkpoints;
+use crate::emitter::Destination;
+use crate::filter::{Container, Filter};
+use crate::multiline::{Multiline, Pattern, Record};
+use crate::parser::Parser;
+use crate::partial::{Limits, Partials};
+use crate::registry::Collector;
+use crate::router::{self, Router};
+use crate::runtime::{Endpoint, Runtime};
+use crate::severity::Classifier;
+use crate::syslog::{Facility, Formatter, Severity, StructuredData};
 use crate::{helpers, EmitterData, ONE_SECOND};
 use bollard::container::{LogOutput, LogsOptions};
 use bollard::models::{ContainerConfig, ContainerInspectResponse, ContainerState};
-use bollard::Docker;
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, TimeDelta, Utc};
 use futures_util::StreamExt;
 use lazy_static::lazy_static;
+use std::borrow::Cow;
 use std::collections::HashMap;
 #[cfg(feature = "exec-by-pid")]
 use std::ffi::OsStr;
+use std::hash::{DefaultHasher, Hash, Hasher};
 #[cfg(feature = "exec-by-pid")]
 use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
 use std::time::SystemTime;
 #[cfg(feature = "exec-by-pid")]
 use sysinfo::{Pid, Process, ProcessRefreshKind, ProcessesToUpdate, System, UpdateKind};
-use tokio::sync::mpsc::Sender;
+use tokio::sync::watch;
+use tokio::time::Instant;
 use tokio_util::sync::CancellationToken;
 
 lazy_static! {
@@ -22,10 +36,99 @@ lazy_static! {
     static ref USE_RFC_3164: bool = std::env::var("SYSLOG_RFC")
         .map(|v| v == "3164")
         .unwrap_or(false);
+    static ref PARTIAL_LIMITS: Result<Limits, String> = Limits::from_env();
+    static ref DRAIN_TIMEOUT: Result<Duration, String> = std::env::var("DRAIN_TIMEOUT")
+        .map_or(Ok(DEFAULT_DRAIN_TIMEOUT_MS), |v| {
+            v.trim()
+                .parse()
+                .map_err(|err| format!("Invalid value for DRAIN_TIMEOUT: {err}"))
+        })
+        .map(Duration::from_millis);
+    static ref ENTERPRISE_NUMBER: Result<String, String> = std::env::var("SD_ENTERPRISE_NUMBER")
+        .map_or_else(
+            |_| Ok(DEFAULT_ENTERPRISE_NUMBER.to_string()),
+            |number| {
+                let number = number.trim();
+                if number
+                    .split('.')
+                    .all(|part| !part.is_empty() && part.bytes().all(|b| b.is_ascii_digit()))
+                {
+                    Ok(number.to_string())
+                } else {
+                    Err(format!(
+                        "Invalid value for SD_ENTERPRISE_NUMBER: \"{number}\""
+                    ))
+                }
+            },
+        );
+    static ref FACILITY: Facility = env_or_default(
+        "SYSLOG_FACILITY",
+        Facility::from_name,
+        Facility::SystemDaemon
+    );
+    static ref STDOUT_SEVERITY: Severity = env_or_default(
+        "STDOUT_SEVERITY",
+        Severity::from_name,
+        Severity::Informational
+    );
+    static ref STDERR_SEVERITY: Severity =
+        env_or_default("STDERR_SEVERITY", Severity::from_name, Severity::Error);
+    static ref SD_CONTAINER_METADATA: bool =
+        std::env::var("SD_CONTAINER_METADATA").map_or(true, |v| helpers::bool_from_str(v.as_str()));
+    static ref SD_LABELS: Vec<String> = std::env::var("SD_LABELS")
+        .map(|v| {
+            v.split(',')
+                .map(str::trim)
+                .filter(|label| !label.is_empty())
+                .map(String::from)
+                .collect()
+        })
+        .unwrap_or_default();
     static ref USE_EXEC_PID: bool =
         std::env::var("USE_EXEC_PID").map_or(true, |v| helpers::bool_from_str(v.as_str()));
 }
 
+/// Private enterprise number reserved for documentation by RFC 5612
+const DEFAULT_ENTERPRISE_NUMBER: &str = "32473";
+
+/// SD-ID of the structured data element with the given name, qualified by the private
+/// enterprise number configured by `SD_ENTERPRISE_NUMBER`
+pub fn sd_id(name: &str) -> String {
+    let number = ENTERPRISE_NUMBER
+        .as_deref()
+        .unwrap_or(DEFAULT_ENTERPRISE_NUMBER);
+    format!("{name}@{number}")
+}
+
+fn env_or_default<T>(name: &str, parse: fn(&str) -> Option<T>, default: T) -> T {
+    match std::env::var(name) {
+        Ok(value) => parse(&value).unwrap_or_else(|| {
+            tracing::warn!("Ignoring invalid {name} \"{value}\"");
+            default
+        }),
+        Err(_) => default,
+    }
+}
+
+fn label_or_default<T>(
+    labels: &HashMap<String, String>,
+    name: &str,
+    parse: fn(&str) -> Option<T>,
+    default: T,
+    container_id: &str,
+) -> T {
+    let label = format!("de.hammer065.docker-log-emitter.{name}");
+    match labels.get(&label) {
+        Some(value) => parse(value).unwrap_or_else(|| {
+            tracing::warn!(
+                "Ignoring invalid label {label} \"{value}\" of container \"{container_id}\""
+            );
+            default
+        }),
+        None => default,
+    }
+}
+
 #[cfg(feature = "exec-by-pid")]
 struct ExecByPid {
     system: System,
@@ -82,6 +185,80 @@ impl ExecByPid {
     }
 }
 
+fn content_hash(msg: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    msg.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Position of the last emitted log line, used to skip already emitted lines after reconnecting
+#[derive(Default)]
+struct ResumePoint {
+    ts: Option<DateTime<Utc>>,
+    /// Occurrences of the emitted lines sharing the last timestamp by content hash, `None` if
+    /// unknown
+    emitted: Option<HashMap<u64, usize>>,
+    /// Occurrences of the lines sharing the last timestamp read since reconnecting
+    replayed: HashMap<u64, usize>,
+    /// Whether the log stream replays lines up to the last timestamp, which ends with the first
+    /// newer line
+    replaying: bool,
+}
+
+impl ResumePoint {
+    fn from_checkpoint(ts: DateTime<Utc>) -> Self {
+        Self {
+            ts: Some(ts),
+            emitted: None,
+            replayed: HashMap::new(),
+            replaying: true,
+        }
+    }
+
+    fn since(&self) -> Option<i64> {
+        // The Docker API only accepts whole seconds
+        self.ts.map(|ts| ts.timestamp())
+    }
+
+    /// Starts counting the replayed lines of a new log stream
+    fn reconnect(&mut self) {
+        self.replayed.clear();
+        self.replaying = self.ts.is_some();
+    }
+
+    /// Whether the line got emitted before reconnecting, otherwise records it as emitted.
+    /// Identical lines sharing a timestamp are told apart by their number of occurrences.
+    /// Outside of the replay, lines are never skipped, even if the clock stepped back or the
+    /// output streams got interleaved slightly out of order.
+    fn is_emitted(&mut self, ts: DateTime<Utc>, msg: &[u8]) -> bool {
+        match self.ts {
+            Some(last) if ts < last => self.replaying,
+            Some(last) if ts == last => {
+                let hash = content_hash(msg);
+                let Some(emitted) = self.emitted.as_mut() else {
+                    return self.replaying;
+                };
+                let count = emitted.entry(hash).or_default();
+                if self.replaying {
+                    let replayed = self.replayed.entry(hash).or_default();
+                    *replayed += 1;
+                    if *replayed <= *count {
+                        return true;
+                    }
+                }
+                *count += 1;
+                false
+            }
+            _ => {
+                self.ts = Some(ts);
+                self.emitted = Some(HashMap::from([(content_hash(msg), 1)]));
+                self.replaying = false;
+                false
+            }
+        }
+    }
+}
+
 fn exec_by_container_info(
     container_path: Option<&str>,
     container_name: Option<&str>,
@@ -109,43 +286,322 @@ fn parse_log_line(line: &[u8]) -> Option<(DateTime<Utc>, &[u8])> {
     }
 }
 
-async fn handle_log_line(
-    line: LogOutput,
-    formatter: &Formatter,
-    tx: &Sender<EmitterData>,
-    static_app_name: Option<&str>,
-    #[cfg(feature = "exec-by-pid")] exec_by_pid: Option<&mut ExecByPid>,
-) -> Option<i64> {
-    let (is_err, message) = match line {
-        LogOutput::StdErr { message } => (true, message),
-        LogOutput::StdOut { message }
-        | LogOutput::StdIn { message }
-        | LogOutput::Console { message } => (false, message),
+fn handle_log_line(
+    line: &LogOutput,
+    resume_point: &mut ResumePoint,
+    partials: &mut Partials,
+    multiline: &mut Multiline,
+) -> Batch {
+    let (is_err, is_tty, message) = match line {
+        LogOutput::StdErr { message } => (true, false, message),
+        LogOutput::StdOut { message } | LogOutput::StdIn { message } => (false, false, message),
+        LogOutput::Console { message } => (false, true, message),
     };
-    let (ts, msg) = parse_log_line(message.as_ref())?;
+    let Some((ts, msg)) = parse_log_line(message.as_ref()) else {
+        return Batch::default();
+    };
+    if resume_point.is_emitted(ts, msg) {
+        tracing::trace!("Skipping already emitted log line");
+        return Batch::default();
+    }
 
-    let severity = if is_err {
-        &Severity::Error
+    // TTY output is not split into partial messages, but may lack trailing newlines
+    let records = if is_tty {
+        multiline.push(is_err, ts, msg)
     } else {
-        &Severity::Informational
+        partials
+            .push(is_err, ts, msg)
+            .map_or_else(Vec::new, |(ts, msg)| multiline.push(is_err, ts, &msg))
     };
+    Batch::new(records, partials, multiline)
+}
+
+/// Milliseconds to wait for further log lines of a detached container before ending its collector
+const DEFAULT_DRAIN_TIMEOUT_MS: u64 = 2000;
+
+fn drain_timeout() -> Duration {
+    DRAIN_TIMEOUT
+        .as_ref()
+        .map_or(Duration::from_millis(DEFAULT_DRAIN_TIMEOUT_MS), |timeout| {
+            *timeout
+        })
+}
+
+/// Name of the structured data element holding the container metadata
+const METADATA_SD_NAME: &str = "container";
+
+fn is_sd_label(label: &str) -> bool {
+    SD_LABELS.iter().any(|pattern| {
+        pattern
+            .strip_suffix('*')
+            .map_or(pattern == label, |prefix| label.starts_with(prefix))
+    })
+}
+
+fn container_metadata(
+    container_info: &ContainerInspectResponse,
+    container_name: Option<&str>,
+    pod: Option<&str>,
+    labels: &HashMap<String, String>,
+) -> StructuredData {
+    let mut structured_data = StructuredData::default();
+    if !*SD_CONTAINER_METADATA {
+        return structured_data;
+    }
+
+    let image = container_info
+        .config
+        .as_ref()
+        .and_then(|config| config.image.as_deref());
+    let mut params: Vec<(&str, &str)> = [
+        ("id", container_info.id.as_deref()),
+        ("name", container_name),
+        ("image", image),
+        ("image_id", container_info.image.as_deref()),
+        ("pod", pod),
+        (
+            "compose_project",
+            labels.get("com.docker.compose.project").map(String::as_str),
+        ),
+        (
+            "compose_service",
+            labels.get("com.docker.compose.service").map(String::as_str),
+        ),
+    ]
+    .into_iter()
+    .filter_map(|(name, value)| Some((name, value?)))
+    .collect();
+
+    let mut sd_labels: Vec<_> = labels
+        .iter()
+        .filter(|(label, _)| is_sd_label(label))
+        .map(|(label, value)| (label.as_str(), value.as_str()))
+        .collect();
+    sd_labels.sort_unstable();
+    params.extend(sd_labels);
+
+    structured_data.add(&sd_id(METADATA_SD_NAME), params);
+    structured_data
+}
+
+/// Completed log records, together with the timestamp of the earliest line still pending
+#[derive(Default)]
+struct Batch {
+    records: Vec<Record>,
+    pending_since: Option<DateTime<Utc>>,
+}
+
+impl Batch {
+    fn new(records: Vec<Record>, partials: &Partials, multiline: &Multiline) -> Self {
+        let pending_since = match (partials.pending_since(), multiline.pending_since()) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        Self {
+            records,
+            pending_since,
+        }
+    }
+
+    /// Records with the checkpoint after each of them. Lines of the following records and
+    /// the pending ones of the other stream may precede a record, but get emitted after it,
+    /// so its checkpoint must not pass them.
+    fn checkpointed(self) -> Vec<(Record, DateTime<Utc>)> {
+        let mut pending_since = self.pending_since;
+        let mut records: Vec<_> = self
+            .records
+            .into_iter()
+            .rev()
+            .map(|record| {
+                let checkpoint = match pending_since {
+                    // Lines up to the checkpoint count as emitted when resuming
+                    Some(since) if since <= record.last_ts => since - TimeDelta::nanoseconds(1),
+                    _ => record.last_ts,
+                };
+                pending_since = Some(pending_since.map_or(record.ts, |since| since.min(record.ts)));
+                (record, checkpoint)
+            })
+            .collect();
+        records.reverse();
+        records
+    }
+}
+
+/// Records of the incomplete log lines and multiline records whose flush timeout expired
+fn flush_expired(partials: &mut Partials, multiline: &mut Multiline) -> Batch {
+    let mut records = Vec::new();
+    for (is_err, ts, msg) in partials.flush_expired() {
+        records.extend(multiline.push(is_err, ts, &msg));
+    }
+    records.extend(multiline.flush_expired());
+    Batch::new(records, partials, multiline)
+}
+
+fn flush_log_lines(partials: &mut Partials, multiline: &mut Multiline) -> Batch {
+    let mut records = Vec::new();
+    for (is_err, ts, msg) in partials.flush() {
+        records.extend(multiline.push(is_err, ts, &msg));
+    }
+    records.extend(multiline.flush());
+    Batch::new(records, partials, multiline)
+}
+
+/// Formats and queues the log records of a container
+struct LogSink<'a> {
+    formatter: Formatter,
+    destinations: Vec<Arc<Destination>>,
+    checkpoints: &'a Arc<Checkpoints>,
+    checkpoint_scope: &'a str,
+    container_id: &'a str,
+    static_app_name: Option<String>,
+    stdout_severity: Severity,
+    stderr_severity: Severity,
+    metadata: StructuredData,
+    parser: Option<Parser>,
+    classifier: Option<Classifier>,
     #[cfg(feature = "exec-by-pid")]
-    let app_name = static_app_name.or_else(|| exec_by_pid.and_then(ExecByPid::app_name));
-    #[cfg(not(feature = "exec-by-pid"))]
-    let app_name = static_app_name;
+    exec_by_pid: Option<ExecByPid>,
+    /// Whether the container is selected by the filter, otherwise log lines get discarded
+    enabled: bool,
+}
 
-    let data = formatter.format(msg, app_name, severity, &ts);
+impl<'a> LogSink<'a> {
+    fn new(
+        container_info: &ContainerInspectResponse,
+        pod: Option<&str>,
+        endpoint: &'a Endpoint,
+        destinations: Vec<Arc<Destination>>,
+        checkpoints: &'a Arc<Checkpoints>,
+        container_id: &'a str,
+    ) -> Self {
+        let (container_name, pid, labels, _) = container_infos(container_info);
 
-    if let Err(err) = tx.send(data).await {
-        tracing::error!("Failed to queue log message: {}", err);
-    };
+        let formatter = get_formatter(
+            facility(labels, container_id),
+            &endpoint.hostname,
+            pid,
+            container_name.as_deref(),
+        );
 
-    Some(ts.timestamp())
+        let mut static_app_name = labels
+            .get("de.hammer065.docker-log-emitter.app_name")
+            .map(String::from);
+        if cfg!(not(feature = "exec-by-pid")) || !*USE_EXEC_PID {
+            static_app_name = static_app_name.or_else(|| {
+                exec_by_container_info(container_info.path.as_deref(), container_name.as_deref())
+            });
+        }
+
+        let parser = Parser::from_labels(labels, *USE_RFC_3164).unwrap_or_else(|err| {
+            tracing::warn!("Disabled parser for container \"{container_id}\": {err}");
+            None
+        });
+        let classifier = Classifier::from_labels(labels).unwrap_or_else(|err| {
+            tracing::warn!("Disabled severity rules for container \"{container_id}\": {err}");
+            None
+        });
+
+        Self {
+            formatter,
+            destinations,
+            checkpoints,
+            checkpoint_scope: endpoint.checkpoint_scope(),
+            container_id,
+            static_app_name,
+            stdout_severity: label_or_default(
+                labels,
+                "stdout_severity",
+                Severity::from_name,
+                *STDOUT_SEVERITY,
+                container_id,
+            ),
+            stderr_severity: label_or_default(
+                labels,
+                "stderr_severity",
+                Severity::from_name,
+                *STDERR_SEVERITY,
+                container_id,
+            ),
+            metadata: container_metadata(container_info, container_name.as_deref(), pod, labels),
+            parser,
+            classifier,
+            #[cfg(feature = "exec-by-pid")]
+            exec_by_pid: get_exec_pid(
+                pid,
+                container_info.path.as_deref(),
+                container_name.as_deref(),
+            ),
+            enabled: true,
+        }
+    }
+
+    async fn emit(&mut self, batch: Batch) {
+        for (record, checkpoint) in batch.checkpointed() {
+            if !self.enabled {
+                self.checkpoints
+                    .set(self.checkpoint_scope, self.container_id, checkpoint);
+                continue;
+            }
+            let parsed = self
+                .parser
+                .as_ref()
+                .and_then(|parser| parser.parse(&record.msg));
+            let (msg, ts) = match parsed.as_ref() {
+                Some(parsed) => (
+                    parsed.msg.as_ref(),
+                    parsed.ts.as_ref().unwrap_or(&record.ts),
+                ),
+                None => (record.msg.as_slice(), &record.ts),
+            };
+            let structured_data = match parsed.as_ref() {
+                Some(parsed) if !parsed.structured_data.is_empty() => {
+                    let mut structured_data = self.metadata.clone();
+                    structured_data.append(&parsed.structured_data);
+                    Cow::Owned(structured_data)
+                }
+                _ => Cow::Borrowed(&self.metadata),
+            };
+            let severity = parsed
+                .as_ref()
+                .and_then(|parsed| parsed.severity)
+                .or_else(|| {
+                    self.classifier
+                        .as_ref()
+                        .and_then(|classifier| classifier.classify(msg))
+                })
+                .unwrap_or(if record.is_err {
+                    self.stderr_severity
+                } else {
+                    self.stdout_severity
+                });
+            #[cfg(feature = "exec-by-pid")]
+            let app_name = self
+                .static_app_name
+                .as_deref()
+                .or_else(|| self.exec_by_pid.as_mut().and_then(ExecByPid::app_name));
+            #[cfg(not(feature = "exec-by-pid"))]
+            let app_name = self.static_app_name.as_deref();
+
+            let data = self
+                .formatter
+                .format(msg, app_name, severity, ts, &structured_data);
+            let ack = self
+                .checkpoints
+                .ack(self.checkpoint_scope, self.container_id, checkpoint);
+
+            router::send(&self.destinations, EmitterData::new(data, ack)).await;
+        }
+    }
 }
 
 fn container_infos(
     container_info: &ContainerInspectResponse,
-) -> (Option<String>, Option<i64>, &HashMap<String, String>, bool) {
+) -> (
+    Option<String>,
+    Option<i64>,
+    &HashMap<String, String>,
+    Option<bool>,
+) {
     let container_name = container_info
         .name
         .as_deref()
@@ -168,14 +624,33 @@ fn container_infos(
     let enabled = labels
         .get("de.hammer065.docker-log-emitter.enabled")
         .map(String::as_str)
-        .map_or(true, helpers::bool_from_str);
+        .map(helpers::bool_from_str);
 
     (container_name, pid, labels, enabled)
 }
 
+/// Validates the environment variables only read once attached to a container
+pub fn check_env() -> Result<(), String> {
+    PARTIAL_LIMITS.as_ref().map_err(Clone::clone)?;
+    DRAIN_TIMEOUT.as_ref().map_err(Clone::clone)?;
+    ENTERPRISE_NUMBER.as_ref().map_err(Clone::clone)?;
+    Ok(())
+}
+
+/// Syslog facility selected by the container labels
+pub fn facility(labels: &HashMap<String, String>, container_id: &str) -> Facility {
+    label_or_default(
+        labels,
+        "facility",
+        Facility::from_name,
+        *FACILITY,
+        container_id,
+    )
+}
+
 #[inline]
-fn get_formatter(
-    facility: &Facility,
+pub fn get_formatter(
+    facility: Facility,
     hostname: &str,
     pid: Option<i64>,
     msgid: Option<&str>,
@@ -202,105 +677,191 @@ fn get_exec_pid(
         .map(|p| ExecByPid::new(p, exec_by_container_info(container_path, container_name)))
 }
 
+async fn inspect(
+    runtime: &Runtime,
+    container_id: &str,
+) -> Option<watch::Receiver<Arc<ContainerInspectResponse>>> {
+    match runtime.metadata.get(container_id).await {
+        Ok(info) => Some(info),
+        Err(err) => {
+            tracing::warn!("Error fetching info for container \"{container_id}\": {err}");
+            None
+        }
+    }
+}
+
+fn is_selected(filter: &Filter, container_info: &ContainerInspectResponse) -> bool {
+    let (container_name, _, labels, enabled) = container_infos(container_info);
+    filter.is_selected(&Container {
+        name: container_name.as_deref(),
+        image: container_info
+            .config
+            .as_ref()
+            .and_then(|config| config.image.as_deref()),
+        project: labels.get("com.docker.compose.project").map(String::as_str),
+        labels,
+        enabled,
+    })
+}
+
+/// Log sink configured by the current container info and labels
+fn log_sink<'a>(
+    container_info: &ContainerInspectResponse,
+    pod: Option<&str>,
+    runtime: &'a Runtime,
+    router: &Router,
+    filter: &Filter,
+    checkpoints: &'a Arc<Checkpoints>,
+    container_id: &'a str,
+) -> LogSink<'a> {
+    let (_, _, labels, _) = container_infos(container_info);
+    let mut sink = LogSink::new(
+        container_info,
+        pod,
+        &runtime.endpoint,
+        router.route(labels, container_id),
+        checkpoints,
+        container_id,
+    );
+    sink.enabled = is_selected(filter, container_info);
+    sink
+}
+
+/// Joins the lines matching the multiline pattern given by the container labels
+fn multiline_from_labels(
+    container_info: &ContainerInspectResponse,
+    container_id: &str,
+) -> Multiline {
+    let (_, _, labels, _) = container_infos(container_info);
+    Multiline::new(Pattern::from_labels(labels).unwrap_or_else(|err| {
+        tracing::warn!("Disabled multiline for container \"{container_id}\": {err}");
+        None
+    }))
+}
+
+/// Follows both output streams from the given UNIX timestamp on
+const fn logs_options(since: i64) -> LogsOptions<&'static str> {
+    LogsOptions {
+        follow: true,
+        stdout: true,
+        stderr: true,
+        since,
+        until: 0,
+        timestamps: true,
+        tail: "all",
+    }
+}
+
 pub async fn collect(
-    container_id: String,
-    tx: Sender<EmitterData>,
+    collector: &Collector,
+    runtime: Arc<Runtime>,
+    router: Arc<Router>,
     cancellation_token: CancellationToken,
-    hostname: &str,
+    checkpoints: Arc<Checkpoints>,
+    filter: Arc<Filter>,
 ) {
+    let container_id = collector.container_id.as_str();
+    let detach_token = &collector.detach_token;
     tracing::trace!("collect(container_id = \"{container_id}\") start");
-    let cid_ref = container_id.as_str();
-    let mut since = helpers::current_timestamp();
+    let endpoint = &runtime.endpoint;
+    // Lines of restarted containers are only read once the previous collector drained its
+    // stream, as both would otherwise emit them
+    let start = helpers::current_timestamp();
+    collector.predecessor_ended().await;
+    let mut resume_point = checkpoints
+        .get(endpoint.checkpoint_scope(), container_id)
+        .map_or_else(ResumePoint::default, ResumePoint::from_checkpoint);
+    let mut partials = Partials::new(PARTIAL_LIMITS.clone().unwrap_or_default());
+    let mut multiline: Option<Multiline> = None;
 
     'outer_loop: loop {
-        if cancellation_token.is_cancelled() {
+        if cancellation_token.is_cancelled() || detach_token.is_cancelled() {
             tracing::trace!("collect(container_id = \"{container_id}\") end");
             return;
         }
 
-        let docker = match Docker::connect_with_defaults() {
-            Ok(docker) => docker,
-            Err(err) => {
-                tracing::warn!(
-                    "Error connecting to Docker for container \"{container_id}\": {err}"
-                );
-                break;
-            }
-        };
-
-        let container_info = match docker.inspect_container(cid_ref, None).await {
-            Ok(info) => info,
-            Err(err) => {
-                tracing::warn!("Error fetching info for container \"{container_id}\": {err}");
-                break;
-            }
+        let Some(mut updates) = inspect(&runtime, container_id).await else {
+            break;
         };
-        let (container_name, pid, labels, enabled) = container_infos(&container_info);
-
-        if !enabled {
+        let container_info = updates.borrow_and_update().clone();
+        if !is_selected(&filter, &container_info) {
             tracing::info!("Disabled logging for container \"{container_id}\"");
             tracing::trace!("collect(container_id = \"{container_id}\") end");
             return;
         }
 
-        let formatter = get_formatter(
-            &Facility::SystemDaemon,
-            hostname,
-            pid,
-            container_name.as_deref(),
+        let multiline =
+            multiline.get_or_insert_with(|| multiline_from_labels(&container_info, container_id));
+
+        let pod = runtime.pod_name(container_id).await;
+        let mut sink = log_sink(
+            &container_info,
+            pod.as_deref(),
+            &runtime,
+            &router,
+            &filter,
+            &checkpoints,
+            container_id,
         );
 
-        let mut static_app_name = labels
-            .get("de.hammer065.docker-log-emitter.app_name")
-            .map(String::from);
-        if cfg!(not(feature = "exec-by-pid")) || !*USE_EXEC_PID {
-            static_app_name = static_app_name.or_else(|| {
-                exec_by_container_info(container_info.path.as_deref(), container_name.as_deref())
-            });
-        }
-
-        #[cfg(feature = "exec-by-pid")]
-        let mut exec_by_pid = get_exec_pid(
-            pid,
-            container_info.path.as_deref(),
-            container_name.as_deref(),
-        );
-
-        let logs = &mut docker.logs(
-            cid_ref,
-            Some(LogsOptions {
-                follow: true,
-                stdout: true,
-                stderr: true,
-                since,
-                until: 0,
-                timestamps: true,
-                tail: "all",
-            }),
+        let logs = &mut runtime.docker.logs(
+            container_id,
+            Some(logs_options(resume_point.since().unwrap_or(start))),
         );
 
+        resume_point.reconnect();
         tracing::info!("Attached to container \"{container_id}\"");
         tracing::trace!("collect(container_id = \"{container_id}\") loop");
+        let mut drain_deadline = None;
         loop {
+            let deadline = match (partials.deadline(), multiline.deadline()) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            };
             tokio::select! {
                 biased;
-                () = cancellation_token.cancelled() => break 'outer_loop,
+                () = cancellation_token.cancelled() => {
+                    sink.emit(flush_log_lines(&mut partials, multiline)).await;
+                    break 'outer_loop;
+                },
+                // Stopped or paused containers, whose log streams may not end on their own
+                () = detach_token.cancelled(), if drain_deadline.is_none() => {
+                    drain_deadline = Some(Instant::now() + drain_timeout());
+                },
                 result = logs.next() => {
                     match result {
                         Some(Ok(line)) => {
-                            if let Some(ts) = handle_log_line(line, &formatter, &tx,
-                                static_app_name.as_deref(), #[cfg(feature = "exec-by-pid")] {exec_by_pid.as_mut()}
-                            ).await {
-                                since = ts;
-                            }
+                            drain_deadline = drain_deadline.map(|_| Instant::now() + drain_timeout());
+                            sink.emit(handle_log_line(&line, &mut resume_point, &mut partials, multiline)).await;
                         },
                         Some(Err(err)) => {
                             tracing::warn!("Error while reading log stream of container \"{container_id}\": {err}");
                             break;
                         },
-                        None => break 'outer_loop,
+                        None => {
+                            sink.emit(flush_log_lines(&mut partials, multiline)).await;
+                            break 'outer_loop;
+                        },
                     }
-                }
+                },
+                () = tokio::time::sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                    sink.emit(flush_expired(&mut partials, multiline)).await;
+                },
+                // Detached collectors end once no further lines arrived for the drain timeout
+                () = tokio::time::sleep_until(drain_deadline.unwrap_or_else(Instant::now)), if drain_deadline.is_some() => {
+                    sink.emit(flush_log_lines(&mut partials, multiline)).await;
+                    break 'outer_loop;
+                },
+                // Renamed or updated containers, applied without restarting the log stream
+                Ok(()) = updates.changed() => {
+                    let container_info = updates.borrow_and_update().clone();
+                    sink = log_sink(&container_info, pod.as_deref(), &runtime, &router, &filter, &checkpoints, container_id);
+                    if sink.enabled {
+                        tracing::info!("Updated configuration of container \"{container_id}\"");
+                    } else {
+                        tracing::info!("Disabled logging for container \"{container_id}\"");
+                    }
+                },
             }
         }
     }
@@ -308,3 +869,63 @@ pub async fn collect(
     tracing::info!("Detached from container \"{container_id}\"");
     tracing::trace!("collect(container_id = \"{container_id}\") end");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::ResumePoint;
+    use chrono::{DateTime, TimeDelta, Utc};
+
+    fn ts(secs: i64) -> DateTime<Utc> {
+        DateTime::from_timestamp(secs, 0).expect("valid timestamp")
+    }
+
+    #[test]
+    fn resume_point_skips_replayed_lines() {
+        let mut resume_point = ResumePoint::default();
+        resume_point.reconnect();
+        assert!(!resume_point.is_emitted(ts(10), b"a"));
+        assert!(!resume_point.is_emitted(ts(11), b"b"));
+        assert!(!resume_point.is_emitted(ts(11), b"b"));
+
+        resume_point.reconnect();
+        assert!(resume_point.is_emitted(ts(10), b"a"));
+        assert!(resume_point.is_emitted(ts(11), b"b"));
+        assert!(resume_point.is_emitted(ts(11), b"b"));
+        // A third identical line sharing the timestamp was not emitted before
+        assert!(!resume_point.is_emitted(ts(11), b"b"));
+        assert!(!resume_point.is_emitted(ts(12), b"c"));
+    }
+
+    #[test]
+    fn resume_point_ends_replay_with_newer_line() {
+        let mut resume_point = ResumePoint::default();
+        resume_point.reconnect();
+        assert!(!resume_point.is_emitted(ts(10), b"a"));
+
+        resume_point.reconnect();
+        assert!(!resume_point.is_emitted(ts(12), b"c"));
+        // Out of order lines of the other output stream or a clock stepping back
+        assert!(!resume_point.is_emitted(ts(11), b"b"));
+        assert!(!resume_point.is_emitted(ts(12) - TimeDelta::milliseconds(1), b"d"));
+        assert!(!resume_point.is_emitted(ts(12), b"c"));
+    }
+
+    #[test]
+    fn resume_point_never_skips_while_streaming() {
+        let mut resume_point = ResumePoint::default();
+        assert!(!resume_point.is_emitted(ts(20), b"a"));
+        assert!(!resume_point.is_emitted(ts(10), b"b"));
+        assert!(!resume_point.is_emitted(ts(20), b"a"));
+    }
+
+    #[test]
+    fn resume_point_from_checkpoint_skips_up_to_its_timestamp() {
+        let mut resume_point = ResumePoint::from_checkpoint(ts(10));
+        assert_eq!(resume_point.since(), Some(10));
+        assert!(resume_point.is_emitted(ts(9), b"a"));
+        // Lines at the checkpoint are unknown and thereby considered emitted
+        assert!(resume_point.is_emitted(ts(10), b"b"));
+        assert!(!resume_point.is_emitted(ts(11), b"c"));
+        assert!(!resume_point.is_emitted(ts(10), b"d"));
+    }
+}
@@ -1,89 +1,253 @@
+#[cfg(feature = "spool")]
+use crate::spool::Spool;
+#[cfg(feature = "tls")]
+use crate::tls;
 use crate::{EmitterData, ONE_SECOND};
+use futures_util::future::join_all;
+use std::borrow::Cow;
 use std::future::{pending, Future};
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::path::{Path, PathBuf};
 use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpStream, UdpSocket};
+use tokio::net::{TcpStream, UdpSocket, UnixDatagram, UnixStream};
 use tokio::signal::unix::SignalKind;
-use tokio::sync::mpsc::Receiver;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::time::Instant;
+#[cfg(feature = "tls")]
+use tokio_rustls::{client::TlsStream, rustls::pki_types::ServerName, TlsConnector};
 use tokio_util::sync::CancellationToken;
 use tracing::log;
 
+type EmitterTask = Pin<Box<dyn Future<Output = ()> + Send>>;
+
 const ZERO_V4: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
 const ZERO_V6: SocketAddr = SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0);
 
+pub const QUEUE_SIZE: usize = 1024;
+
 const MAX_UDP_PACKET_SIZE: usize = 65_507;
+const MAX_UNIX_DATAGRAM_SIZE: usize = 65_536;
+
+/// Time to write a log line before the connection is considered broken
+const SEND_TIMEOUT: Duration = Duration::from_secs(10);
+/// Time to connect a socket including the TLS handshake, so unreachable targets fail over
+/// quickly and failback checks do not stall the active target
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Copy)]
+enum Framing {
+    NonTransparent,
+    OctetCounting,
+}
 
-struct SocketOptions<T> {
-    addr: SocketAddr,
+impl Framing {
+    fn from_env() -> Result<Option<Self>, String> {
+        match std::env::var("EMITTER_FRAMING").as_deref() {
+            Err(_) => Ok(None),
+            Ok("non-transparent") => Ok(Some(Self::NonTransparent)),
+            Ok("octet-counting") => Ok(Some(Self::OctetCounting)),
+            Ok(framing) => Err(format!("Unknown framing \"{framing}\"")),
+        }
+    }
+
+    /// Frames a newline terminated message, whose inner newlines only octet-counting keeps
+    fn frame(self, data: &[u8]) -> Cow<'_, [u8]> {
+        match self {
+            Self::NonTransparent => {
+                let msg = data.strip_suffix(b"\n").unwrap_or(data);
+                if !msg.iter().any(|b| matches!(*b, b'\n' | b'\r')) {
+                    return Cow::Borrowed(data);
+                }
+                // Keep the lines of multiline messages apart
+                let mut frame: Vec<u8> = msg
+                    .iter()
+                    .filter(|b| **b != b'\r')
+                    .map(|b| if *b == b'\n' { b' ' } else { *b })
+                    .collect();
+                frame.push(b'\n');
+                Cow::Owned(frame)
+            }
+            Self::OctetCounting => {
+                let data = data.strip_suffix(b"\n").unwrap_or(data);
+                let mut frame = format!("{} ", data.len()).into_bytes();
+                frame.extend_from_slice(data);
+                Cow::Owned(frame)
+            }
+        }
+    }
+}
+
+struct SocketOptions<T, A = SocketAddr> {
+    addr: A,
+    framing: Framing,
     socket: Option<T>,
 }
 
+#[cfg(feature = "tls")]
+struct TlsOptions {
+    host: String,
+    port: u16,
+    server_name: ServerName<'static>,
+    connector: TlsConnector,
+    framing: Framing,
+    socket: Option<TlsStream<TcpStream>>,
+}
+
 enum SocketSender {
     Tcp(SocketOptions<TcpStream>),
+    #[cfg(feature = "tls")]
+    Tls(Box<TlsOptions>),
     Udp(SocketOptions<UdpSocket>),
+    Unix(SocketOptions<UnixStream, PathBuf>),
+    UnixDatagram(SocketOptions<UnixDatagram, PathBuf>),
+}
+
+/// Strips the trailing newline and truncates the data to fit into a single datagram
+fn datagram(data: &[u8], max_size: usize) -> &[u8] {
+    let data = data.strip_suffix(b"\n").unwrap_or(data);
+    if data.len() > max_size {
+        tracing::warn!(
+            "Truncating log line of {} bytes to fit into a datagram",
+            data.len()
+        );
+    }
+    &data[..data.len().min(max_size)]
+}
+
+/// Errors caused by the data itself, which no retry can resolve
+fn is_permanent(err: &std::io::Error) -> bool {
+    err.raw_os_error() == Some(libc::EMSGSIZE)
 }
 
 impl SocketSender {
-    pub const fn tcp(addr: SocketAddr) -> Self {
-        Self::Tcp(SocketOptions { addr, socket: None })
+    pub const fn tcp(addr: SocketAddr, framing: Framing) -> Self {
+        Self::Tcp(SocketOptions {
+            addr,
+            framing,
+            socket: None,
+        })
+    }
+    #[cfg(feature = "tls")]
+    pub fn tls(
+        host: &str,
+        port: u16,
+        connector: TlsConnector,
+        framing: Framing,
+    ) -> Result<Self, String> {
+        let server_name = ServerName::try_from(host.to_string())
+            .map_err(|err| format!("Invalid server name \"{host}\": {err}"))?;
+
+        Ok(Self::Tls(Box::new(TlsOptions {
+            host: host.to_string(),
+            port,
+            server_name,
+            connector,
+            framing,
+            socket: None,
+        })))
     }
     pub const fn udp(addr: SocketAddr) -> Self {
-        Self::Udp(SocketOptions { addr, socket: None })
+        Self::Udp(SocketOptions {
+            addr,
+            framing: Framing::NonTransparent,
+            socket: None,
+        })
+    }
+    pub const fn unix(path: PathBuf, framing: Framing) -> Self {
+        Self::Unix(SocketOptions {
+            addr: path,
+            framing,
+            socket: None,
+        })
+    }
+    pub const fn unix_datagram(path: PathBuf) -> Self {
+        Self::UnixDatagram(SocketOptions {
+            addr: path,
+            framing: Framing::NonTransparent,
+            socket: None,
+        })
     }
 
-    async fn connect(&mut self) {
-        tracing::trace!("SocketSender::connect() start");
+    const fn is_connected(&self) -> bool {
         match self {
-            Self::Tcp(options) => loop {
-                if options.socket.is_some() {
-                    tracing::trace!("SocketSender::connect() end");
-                    return;
-                }
+            Self::Tcp(options) => options.socket.is_some(),
+            #[cfg(feature = "tls")]
+            Self::Tls(options) => options.socket.is_some(),
+            Self::Udp(options) => options.socket.is_some(),
+            Self::Unix(options) => options.socket.is_some(),
+            Self::UnixDatagram(options) => options.socket.is_some(),
+        }
+    }
 
-                let socket = match TcpStream::connect(options.addr).await {
-                    Ok(socket) => socket,
-                    Err(err) => {
-                        tracing::warn!("Error connecting socket: {err}");
-                        tokio::time::sleep(ONE_SECOND).await;
-                        continue;
-                    }
-                };
+    async fn try_connect(&mut self) -> Result<(), String> {
+        tokio::time::timeout(CONNECT_TIMEOUT, self.connect_socket())
+            .await
+            .unwrap_or_else(|_| Err("Timed out connecting socket".to_string()))
+    }
 
+    async fn connect_socket(&mut self) -> Result<(), String> {
+        match self {
+            Self::Tcp(options) => {
+                let socket = TcpStream::connect(options.addr)
+                    .await
+                    .map_err(|err| format!("Error connecting socket: {err}"))?;
                 options.socket = Some(socket);
-                break;
-            },
-            Self::Udp(options) => loop {
-                if options.socket.is_some() {
-                    tracing::trace!("SocketSender::connect() end");
-                    return;
-                }
-
-                let socket = match UdpSocket::bind(if options.addr.is_ipv4() {
+            }
+            #[cfg(feature = "tls")]
+            Self::Tls(options) => {
+                let socket = TcpStream::connect((options.host.as_str(), options.port))
+                    .await
+                    .map_err(|err| format!("Error connecting socket: {err}"))?;
+                let socket = options
+                    .connector
+                    .connect(options.server_name.clone(), socket)
+                    .await
+                    .map_err(|err| format!("Error during TLS handshake: {err}"))?;
+                options.socket = Some(socket);
+            }
+            Self::Udp(options) => {
+                let socket = UdpSocket::bind(if options.addr.is_ipv4() {
                     ZERO_V4
                 } else {
                     ZERO_V6
                 })
                 .await
-                {
-                    Ok(socket) => socket,
-                    Err(err) => {
-                        tracing::warn!("Error building socket: {err}");
-                        tokio::time::sleep(ONE_SECOND).await;
-                        continue;
-                    }
-                };
-
-                if let Err(err) = socket.connect(options.addr).await {
-                    tracing::warn!("Error connecting socket: {err}");
-                    tokio::time::sleep(ONE_SECOND).await;
-                    continue;
-                }
-
+                .map_err(|err| format!("Error building socket: {err}"))?;
+                socket
+                    .connect(options.addr)
+                    .await
+                    .map_err(|err| format!("Error connecting socket: {err}"))?;
                 options.socket = Some(socket);
-                break;
-            },
+            }
+            Self::Unix(options) => {
+                let socket = UnixStream::connect(&options.addr)
+                    .await
+                    .map_err(|err| format!("Error connecting socket: {err}"))?;
+                options.socket = Some(socket);
+            }
+            Self::UnixDatagram(options) => {
+                let socket = UnixDatagram::unbound()
+                    .map_err(|err| format!("Error building socket: {err}"))?;
+                socket
+                    .connect(&options.addr)
+                    .map_err(|err| format!("Error connecting socket: {err}"))?;
+                options.socket = Some(socket);
+            }
+        }
+        Ok(())
+    }
+
+    async fn connect(&mut self) {
+        tracing::trace!("SocketSender::connect() start");
+        while !self.is_connected() {
+            if let Err(err) = self.try_connect().await {
+                tracing::warn!("{err}");
+                tokio::time::sleep(ONE_SECOND).await;
+            }
         }
         tracing::trace!("SocketSender::connect() end");
     }
@@ -92,43 +256,100 @@ impl SocketSender {
         tracing::trace!("SocketSender::disconnect() start");
         match self {
             Self::Tcp(options) => options.socket = None,
+            #[cfg(feature = "tls")]
+            Self::Tls(options) => options.socket = None,
             Self::Udp(options) => options.socket = None,
+            Self::Unix(options) => options.socket = None,
+            Self::UnixDatagram(options) => options.socket = None,
         }
         tracing::trace!("SocketSender::disconnect() end");
     }
 
+    /// Writes the data within the send timeout. The caller has to disconnect on errors, as a
+    /// write interrupted by the timeout may leave a torn frame on the stream.
+    async fn write(&mut self, data: &[u8]) -> std::io::Result<()> {
+        tokio::time::timeout(SEND_TIMEOUT, self.write_frame(data))
+            .await
+            .unwrap_or_else(|_| {
+                Err(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    "timed out",
+                ))
+            })
+    }
+
+    async fn write_frame(&mut self, data: &[u8]) -> std::io::Result<()> {
+        match self {
+            Self::Tcp(options) => {
+                let data = options.framing.frame(data);
+                let socket = options.socket.as_mut().expect("Connected prior");
+                match socket.write_all(&data).await {
+                    Ok(()) => socket.flush().await,
+                    Err(err) => Err(err),
+                }
+            }
+            #[cfg(feature = "tls")]
+            Self::Tls(options) => {
+                let data = options.framing.frame(data);
+                let socket = options.socket.as_mut().expect("Connected prior");
+                match socket.write_all(&data).await {
+                    Ok(()) => socket.flush().await,
+                    Err(err) => Err(err),
+                }
+            }
+            Self::Udp(options) => options
+                .socket
+                .as_ref()
+                .expect("Connected prior")
+                .send(datagram(data, MAX_UDP_PACKET_SIZE))
+                .await
+                .map(|_| ()),
+            Self::Unix(options) => {
+                let data = options.framing.frame(data);
+                let socket = options.socket.as_mut().expect("Connected prior");
+                match socket.write_all(&data).await {
+                    Ok(()) => socket.flush().await,
+                    Err(err) => Err(err),
+                }
+            }
+            Self::UnixDatagram(options) => options
+                .socket
+                .as_ref()
+                .expect("Connected prior")
+                .send(datagram(data, MAX_UNIX_DATAGRAM_SIZE))
+                .await
+                .map(|_| ()),
+        }
+    }
+
+    /// Tries to send the data once, connecting beforehand if required
+    pub async fn try_send(&mut self, data: &[u8]) -> Result<(), String> {
+        if !self.is_connected() {
+            self.try_connect().await?;
+        }
+        match self.write(data).await {
+            Err(err) if is_permanent(&err) => {
+                tracing::warn!("Dropping log line of {} bytes: {err}", data.len());
+                Ok(())
+            }
+            Err(err) => {
+                self.disconnect();
+                Err(format!("Error sending data: {err}"))
+            }
+            Ok(()) => Ok(()),
+        }
+    }
+
     pub async fn send(&mut self, data: &[u8]) {
         tracing::trace!("SocketSender::send() start");
         loop {
             self.connect().await;
-            let result = match self {
-                Self::Tcp(options) => {
-                    let socket = options.socket.as_mut().expect("Connected prior");
-                    match socket.write_all(data).await {
-                        Ok(()) => socket.flush().await,
-                        Err(err) => Err(err),
-                    }
-                }
-                Self::Udp(options) => {
-                    let data = if data.len() > MAX_UDP_PACKET_SIZE {
-                        // Strip to max UDP packet size
-                        &data[..MAX_UDP_PACKET_SIZE]
-                    } else {
-                        // Just strip newline
-                        &data[..data.len() - 1]
-                    };
-
-                    options
-                        .socket
-                        .as_ref()
-                        .expect("Connected prior")
-                        .send(data)
-                        .await
-                        .map(|_| ())
-                }
-            };
-            match result {
+            match self.write(data).await {
                 Ok(()) => break,
+                Err(err) if is_permanent(&err) => {
+                    tracing::warn!("Dropping log line of {} bytes: {err}", data.len());
+                    break;
+                }
                 Err(err) => {
                     tracing::warn!("Error sending data: {err}");
                     self.disconnect();
@@ -146,10 +367,23 @@ impl SocketSender {
                 socket: Some(socket),
                 ..
             }) => socket.read(&mut empty_buf).await,
+            #[cfg(feature = "tls")]
+            Self::Tls(options) if options.socket.is_some() => {
+                let socket = options.socket.as_mut().expect("Checked prior");
+                socket.read(&mut empty_buf).await
+            }
             Self::Udp(SocketOptions {
                 socket: Some(socket),
                 ..
             }) => socket.recv(&mut empty_buf).await,
+            Self::Unix(SocketOptions {
+                socket: Some(socket),
+                ..
+            }) => socket.read(&mut empty_buf).await,
+            Self::UnixDatagram(SocketOptions {
+                socket: Some(socket),
+                ..
+            }) => socket.recv(&mut empty_buf).await,
             _ => pending().await,
         }
         .unwrap_or(0);
@@ -158,36 +392,78 @@ impl SocketSender {
     pub const fn protocol(&self) -> &'static str {
         match self {
             Self::Tcp(_) => "tcp",
+            #[cfg(feature = "tls")]
+            Self::Tls(_) => "tls",
             Self::Udp(_) => "udp",
+            Self::Unix(_) => "unix",
+            Self::UnixDatagram(_) => "unixgram",
         }
     }
 
-    pub const fn socket_addr(&self) -> &SocketAddr {
+    pub fn address(&self) -> String {
         match self {
-            Self::Tcp(options) => &options.addr,
-            Self::Udp(options) => &options.addr,
+            Self::Tcp(options) => options.addr.to_string(),
+            #[cfg(feature = "tls")]
+            Self::Tls(options) => format!("{}:{}", options.host, options.port),
+            Self::Udp(options) => options.addr.to_string(),
+            Self::Unix(options) => options.addr.display().to_string(),
+            Self::UnixDatagram(options) => options.addr.display().to_string(),
         }
     }
 
     pub fn url(&self) -> String {
-        match self {
-            Self::Tcp(options) => format!("tcp://{}", options.addr),
-            Self::Udp(options) => format!("udp://{}", options.addr),
-        }
+        format!("{}://{}", self.protocol(), self.address())
     }
 }
 
-async fn socket(
-    mut socket_sender: SocketSender,
+/// Socket based emission target, either a single socket or a list of failover targets
+trait Transport: Send {
+    fn url(&self) -> String;
+    fn send(&mut self, data: &[u8]) -> impl Future<Output = ()> + Send;
+    #[cfg(feature = "spool")]
+    fn try_send(&mut self, data: &[u8]) -> impl Future<Output = Result<(), String>> + Send;
+    fn clear_receive(&mut self) -> impl Future<Output = ()> + Send;
+}
+
+impl Transport for SocketSender {
+    fn url(&self) -> String {
+        Self::url(self)
+    }
+    async fn send(&mut self, data: &[u8]) {
+        Self::send(self, data).await;
+    }
+    #[cfg(feature = "spool")]
+    async fn try_send(&mut self, data: &[u8]) -> Result<(), String> {
+        Self::try_send(self, data).await
+    }
+    async fn clear_receive(&mut self) {
+        Self::clear_receive(self).await;
+    }
+}
+
+impl Transport for Failover {
+    fn url(&self) -> String {
+        Self::url(self)
+    }
+    async fn send(&mut self, data: &[u8]) {
+        Self::send(self, data).await;
+    }
+    #[cfg(feature = "spool")]
+    async fn try_send(&mut self, data: &[u8]) -> Result<(), String> {
+        Self::try_send(self, data).await
+    }
+    async fn clear_receive(&mut self) {
+        Self::clear_receive(self).await;
+    }
+}
+
+async fn socket<T: Transport>(
+    mut transport: T,
     cancellation_token: CancellationToken,
     mut rx: Receiver<EmitterData>,
 ) {
-    tracing::trace!(
-        "socket(protocol = \"{}\", addr = \"{}\") start",
-        socket_sender.protocol(),
-        socket_sender.socket_addr()
-    );
-    tracing::info!("Sending logs to {}", socket_sender.url());
+    tracing::trace!("socket(url = \"{}\") start", transport.url());
+    tracing::info!("Sending logs to {}", transport.url());
     loop {
         tokio::select! {
             biased;
@@ -197,19 +473,88 @@ async fn socket(
                     None => break,
                     Some(data) => tokio::select! {
                         biased;
-                        () = socket_sender.send(&data) => {},
+                        () = transport.send(&data.bytes) => data.emitted(),
                         () = cancellation_token.cancelled() => break,
                     },
                 }
             },
-            () = socket_sender.clear_receive() => {},
+            () = transport.clear_receive() => {},
+        }
+    }
+    tracing::trace!("socket(url = \"{}\") end", transport.url());
+}
+
+/// Like `socket()`, but moves log lines into the spool instead of waiting for the transport
+/// while it is failing, and replays them in order once it recovers
+#[cfg(feature = "spool")]
+async fn spooled_socket<T: Transport>(
+    mut transport: T,
+    mut spool: Spool,
+    cancellation_token: CancellationToken,
+    mut rx: Receiver<EmitterData>,
+) {
+    tracing::trace!("spooled_socket(url = \"{}\") start", transport.url());
+    tracing::info!("Sending logs to {}", transport.url());
+    let mut retry_at = Instant::now();
+    loop {
+        if spool.is_empty() {
+            tokio::select! {
+                biased;
+                () = cancellation_token.cancelled() => break,
+                value = rx.recv() => {
+                    match value {
+                        None => break,
+                        Some(data) => {
+                            if let Err(err) = transport.try_send(&data.bytes).await {
+                                tracing::warn!("{err}, spooling log lines");
+                                retry_at = Instant::now() + ONE_SECOND;
+                                if !spool.push(&data.bytes).await {
+                                    continue;
+                                }
+                            }
+                            data.emitted();
+                        },
+                    }
+                },
+                () = transport.clear_receive() => {},
+            }
+        } else {
+            tokio::select! {
+                () = cancellation_token.cancelled() => break,
+                value = rx.recv() => {
+                    match value {
+                        None => break,
+                        Some(data) => {
+                            if spool.push(&data.bytes).await {
+                                data.emitted();
+                            }
+                        },
+                    }
+                },
+                () = tokio::time::sleep_until(retry_at) => {
+                    if let Some(data) = spool.peek().await {
+                        match transport.try_send(data).await {
+                            Ok(()) => spool.pop(),
+                            Err(err) => {
+                                tracing::warn!("{err}");
+                                retry_at = Instant::now() + ONE_SECOND;
+                            }
+                        }
+                    }
+                },
+            }
+        }
+    }
+
+    // Keep log lines still queued for the next start
+    rx.close();
+    while let Ok(data) = rx.try_recv() {
+        if spool.push(&data.bytes).await {
+            data.emitted();
         }
     }
-    tracing::trace!(
-        "socket(protocol = \"{}\", addr = \"{}\") end",
-        socket_sender.protocol(),
-        socket_sender.socket_addr()
-    );
+    spool.close();
+    tracing::trace!("spooled_socket(url = \"{}\") end", transport.url());
 }
 
 struct MaybeSignal {
@@ -275,11 +620,13 @@ async fn file(path: PathBuf, cancellation_token: CancellationToken, mut rx: Rece
                 match value {
                     None => break,
                     Some(data) => {
-                        if let Err(err) = file.write_all(&data).await {
-                            log::warn!("Could not write to emitter file: {err}");
-                        }
-                        if let Err(err) = file.flush().await {
-                            log::warn!("Could not flush emitter file: {err}");
+                        let frame = Framing::NonTransparent.frame(&data.bytes);
+                        match file.write_all(&frame).await {
+                            Ok(()) => match file.flush().await {
+                                Ok(()) => data.emitted(),
+                                Err(err) => log::warn!("Could not flush emitter file: {err}"),
+                            },
+                            Err(err) => log::warn!("Could not write to emitter file: {err}"),
                         }
                     },
                 }
@@ -289,44 +636,100 @@ async fn file(path: PathBuf, cancellation_token: CancellationToken, mut rx: Rece
     tracing::trace!("file(path = \"{}\") end", path.display());
 }
 
-pub fn start(
-    url: String,
+#[cfg(feature = "tls")]
+fn tls_sender(address: &str, framing: Option<Framing>) -> Result<SocketSender, String> {
+    let Some((host, port)) = address.rsplit_once(':') else {
+        return Err("Error parsing url: missing port".to_string());
+    };
+    let host = host
+        .strip_prefix('[')
+        .and_then(|h| h.strip_suffix(']'))
+        .unwrap_or(host);
+    let port = port
+        .parse()
+        .map_err(|err| format!("Error parsing url: {err}"))?;
+
+    let ca_file = std::env::var_os("EMITTER_TLS_CA_FILE").map(PathBuf::from);
+    let cert_file = std::env::var_os("EMITTER_TLS_CERT_FILE").map(PathBuf::from);
+    let key_file = std::env::var_os("EMITTER_TLS_KEY_FILE").map(PathBuf::from);
+    let connector = tls::connector(
+        ca_file.as_deref(),
+        cert_file.as_deref(),
+        key_file.as_deref(),
+    )?;
+
+    // RFC 5425 mandates octet-counting framing for TLS transports
+    SocketSender::tls(
+        host,
+        port,
+        connector,
+        framing.unwrap_or(Framing::OctetCounting),
+    )
+}
+
+fn socket_sender(url: &str, framing: Option<Framing>) -> Result<SocketSender, String> {
+    let parse_addr = |addr: &str| -> Result<SocketAddr, String> {
+        addr.parse()
+            .map_err(|err| format!("Error parsing url: {err}"))
+    };
+
+    match url {
+        url if url.starts_with("tcp://") => Ok(SocketSender::tcp(
+            parse_addr(&url[6..])?,
+            framing.unwrap_or(Framing::NonTransparent),
+        )),
+        url if url.starts_with("tcp:") => Ok(SocketSender::tcp(
+            parse_addr(&url[4..])?,
+            framing.unwrap_or(Framing::NonTransparent),
+        )),
+        #[cfg(feature = "tls")]
+        url if url.starts_with("tls://") => tls_sender(&url[6..], framing),
+        #[cfg(feature = "tls")]
+        url if url.starts_with("tls:") => tls_sender(&url[4..], framing),
+        url if url.starts_with("udp://") => Ok(SocketSender::udp(parse_addr(&url[6..])?)),
+        url if url.starts_with("udp:") => Ok(SocketSender::udp(parse_addr(&url[4..])?)),
+        url if url.starts_with("unix://") => Ok(SocketSender::unix(
+            PathBuf::from(&url[7..]),
+            framing.unwrap_or(Framing::NonTransparent),
+        )),
+        url if url.starts_with("unix:") => Ok(SocketSender::unix(
+            PathBuf::from(&url[5..]),
+            framing.unwrap_or(Framing::NonTransparent),
+        )),
+        url if url.starts_with("unixgram://") => {
+            Ok(SocketSender::unix_datagram(PathBuf::from(&url[11..])))
+        }
+        url if url.starts_with("unixgram:") => {
+            Ok(SocketSender::unix_datagram(PathBuf::from(&url[9..])))
+        }
+        _ => Err("Unknown url type".to_string()),
+    }
+}
+
+fn destination(
+    url: &str,
+    framing: Option<Framing>,
     cancellation_token: CancellationToken,
     rx: Receiver<EmitterData>,
-) -> Result<Pin<Box<dyn Future<Output = ()> + Send>>, String> {
+) -> Result<EmitterTask, String> {
     match url {
-        url if url.starts_with("tcp://") => match url[6..].parse() {
-            Ok(socket_addr) => Ok(Box::pin(socket(
-                SocketSender::tcp(socket_addr),
-                cancellation_token,
-                rx,
-            ))),
-            Err(err) => Err(format!("Error parsing url: {err}")),
-        },
-        url if url.starts_with("tcp:") => match url[4..].parse() {
-            Ok(socket_addr) => Ok(Box::pin(socket(
-                SocketSender::tcp(socket_addr),
-                cancellation_token,
-                rx,
-            ))),
-            Err(err) => Err(format!("Error parsing url: {err}")),
-        },
-        url if url.starts_with("udp://") => match url[6..].parse() {
-            Ok(socket_addr) => Ok(Box::pin(socket(
-                SocketSender::udp(socket_addr),
-                cancellation_token,
-                rx,
-            ))),
-            Err(err) => Err(format!("Error parsing url: {err}")),
-        },
-        url if url.starts_with("udp:") => match url[4..].parse() {
-            Ok(socket_addr) => Ok(Box::pin(socket(
-                SocketSender::udp(socket_addr),
-                cancellation_token,
-                rx,
-            ))),
-            Err(err) => Err(format!("Error parsing url: {err}")),
-        },
+        url if url.contains('|') => {
+            let socket_senders = url
+                .split('|')
+                .map(|url| socket_sender(url.trim(), framing))
+                .collect::<Result<Vec<_>, _>>()?;
+            let failover = Failover::new(socket_senders)?;
+            #[cfg(feature = "spool")]
+            if let Some(spool) = Spool::from_env(url)? {
+                return Ok(Box::pin(spooled_socket(
+                    failover,
+                    spool,
+                    cancellation_token,
+                    rx,
+                )));
+            }
+            Ok(Box::pin(socket(failover, cancellation_token, rx)))
+        }
         url if url.starts_with("file://") => {
             let path = PathBuf::from(&url[7..]);
             Ok(Box::pin(file(path, cancellation_token, rx)))
@@ -345,6 +748,395 @@ pub fn start(
 
             Ok(Box::pin(file(path, cancellation_token, rx)))
         }
-        _ => Err("Unknown url type".to_string()),
+        url => {
+            let socket_sender = socket_sender(url, framing)?;
+            #[cfg(feature = "spool")]
+            if let Some(spool) = Spool::from_env(url)? {
+                return Ok(Box::pin(spooled_socket(
+                    socket_sender,
+                    spool,
+                    cancellation_token,
+                    rx,
+                )));
+            }
+            Ok(Box::pin(socket(socket_sender, cancellation_token, rx)))
+        }
+    }
+}
+
+fn duration_from_env(key: &str, default: Duration) -> Result<Duration, String> {
+    std::env::var(key).map_or(Ok(default), |v| {
+        v.parse()
+            .map(Duration::from_secs)
+            .map_err(|err| format!("Invalid value for {key}: {err}"))
+    })
+}
+
+/// Ordered list of socket senders, where the first one is the primary and all others are
+/// only used while the previous ones are failing
+struct Failover {
+    socket_senders: Vec<SocketSender>,
+    active: usize,
+    max_failures: u32,
+    failure_timeout: Duration,
+    failback_interval: Duration,
+    failures: u32,
+    failing_since: Option<Instant>,
+    last_failback: Instant,
+}
+
+impl Failover {
+    pub fn new(socket_senders: Vec<SocketSender>) -> Result<Self, String> {
+        let max_failures =
+            std::env::var("EMITTER_FAILOVER_RETRIES").map_or(Ok(3), |v| match v.parse() {
+                Ok(0) => Err(
+                    "Invalid value for EMITTER_FAILOVER_RETRIES: must be at least 1".to_string(),
+                ),
+                Ok(max_failures) => Ok(max_failures),
+                Err(err) => Err(format!("Invalid value for EMITTER_FAILOVER_RETRIES: {err}")),
+            })?;
+
+        Ok(Self {
+            socket_senders,
+            active: 0,
+            max_failures,
+            failure_timeout: duration_from_env(
+                "EMITTER_FAILOVER_TIMEOUT",
+                Duration::from_secs(10),
+            )?,
+            failback_interval: duration_from_env(
+                "EMITTER_FAILBACK_INTERVAL",
+                Duration::from_secs(30),
+            )?,
+            failures: 0,
+            failing_since: None,
+            last_failback: Instant::now(),
+        })
+    }
+
+    fn switch_to(&mut self, index: usize) {
+        self.socket_senders[self.active].disconnect();
+        self.active = index;
+        self.failures = 0;
+        self.failing_since = None;
+        self.last_failback = Instant::now();
+    }
+
+    async fn try_failback(&mut self) {
+        if self.active == 0 || self.last_failback.elapsed() < self.failback_interval {
+            return;
+        }
+        self.last_failback = Instant::now();
+
+        let primary = &mut self.socket_senders[0];
+        match primary.try_connect().await {
+            Ok(()) => {
+                tracing::info!(
+                    "Primary emitter {} recovered, switching back from {}",
+                    self.socket_senders[0].url(),
+                    self.socket_senders[self.active].url()
+                );
+                self.switch_to(0);
+            }
+            Err(err) => {
+                tracing::debug!(
+                    "Primary emitter {} still failing: {err}",
+                    self.socket_senders[0].url()
+                );
+            }
+        }
+    }
+
+    /// Tries to send the data once, switching to the next target if the active one keeps failing
+    pub async fn try_send(&mut self, data: &[u8]) -> Result<(), String> {
+        self.try_failback().await;
+
+        let err = match self.socket_senders[self.active].try_send(data).await {
+            Ok(()) => {
+                self.failures = 0;
+                self.failing_since = None;
+                return Ok(());
+            }
+            Err(err) => err,
+        };
+
+        self.failures += 1;
+        let failing_since = *self.failing_since.get_or_insert_with(Instant::now);
+        if self.failures >= self.max_failures || failing_since.elapsed() >= self.failure_timeout {
+            let next = (self.active + 1) % self.socket_senders.len();
+            tracing::warn!(
+                "Emitter {} failed {} times, switching to {}",
+                self.socket_senders[self.active].url(),
+                self.failures,
+                self.socket_senders[next].url()
+            );
+            self.switch_to(next);
+        }
+
+        Err(err)
+    }
+
+    pub async fn send(&mut self, data: &[u8]) {
+        tracing::trace!("Failover::send() start");
+        loop {
+            let active = self.active;
+            match self.try_send(data).await {
+                Ok(()) => break,
+                Err(err) => {
+                    tracing::warn!("{err}");
+                    // Retry right away after switching to the next target
+                    if self.active == active {
+                        tokio::time::sleep(ONE_SECOND).await;
+                    }
+                }
+            }
+        }
+        tracing::trace!("Failover::send() end");
+    }
+
+    pub async fn clear_receive(&mut self) {
+        self.socket_senders[self.active].clear_receive().await;
+    }
+
+    pub fn url(&self) -> String {
+        self.socket_senders[self.active].url()
+    }
+}
+
+/// Handling of log lines for destinations whose queue is full
+#[derive(Clone, Copy)]
+pub enum Overflow {
+    /// Wait for the destination, holding back all others
+    Block,
+    /// Drop the log lines of the destination until it caught up, keeping the others flowing
+    DropNewest,
+}
+
+impl Overflow {
+    pub fn from_env() -> Result<Self, String> {
+        match std::env::var("EMITTER_QUEUE_OVERFLOW").as_deref() {
+            Ok("block") => Ok(Self::Block),
+            Err(_) | Ok("drop-newest") => Ok(Self::DropNewest),
+            Ok(overflow) => Err(format!("Unknown queue overflow policy \"{overflow}\"")),
+        }
+    }
+}
+
+/// Queue of an emitter, handling full queues by its overflow policy
+pub struct Destination {
+    name: String,
+    tx: Sender<EmitterData>,
+    overflow: Overflow,
+    /// Log lines dropped since the queue was last accepting them
+    dropped: AtomicUsize,
+}
+
+impl Destination {
+    pub fn new(name: &str, tx: Sender<EmitterData>, overflow: Overflow) -> Self {
+        Self {
+            name: name.to_string(),
+            tx,
+            overflow,
+            dropped: AtomicUsize::new(0),
+        }
+    }
+
+    pub async fn push(&self, data: EmitterData) {
+        let result = match self.overflow {
+            Overflow::Block => self
+                .tx
+                .send(data)
+                .await
+                .map_err(|err| TrySendError::Closed(err.0)),
+            Overflow::DropNewest => self.tx.try_send(data),
+        };
+        match result {
+            Ok(()) => {
+                let dropped = self.dropped.swap(0, Ordering::Relaxed);
+                if dropped > 0 {
+                    tracing::info!(
+                        "Emitter \"{}\" caught up again, dropped {dropped} log lines",
+                        self.name
+                    );
+                }
+            }
+            Err(TrySendError::Full(_)) => {
+                if self.dropped.fetch_add(1, Ordering::Relaxed) == 0 {
+                    tracing::warn!(
+                        "Queue of emitter \"{}\" is full, dropping log lines",
+                        self.name
+                    );
+                }
+            }
+            Err(TrySendError::Closed(_)) => {
+                if self.dropped.fetch_add(1, Ordering::Relaxed) == 0 {
+                    tracing::error!("Emitter \"{}\" stopped, dropping log lines", self.name);
+                }
+            }
+        }
+    }
+}
+
+async fn fan_out(
+    mut destinations: Vec<Destination>,
+    tasks: Vec<EmitterTask>,
+    cancellation_token: CancellationToken,
+    mut rx: Receiver<EmitterData>,
+) {
+    tracing::trace!("fan_out() start");
+    let distribute = async {
+        loop {
+            tokio::select! {
+                biased;
+                () = cancellation_token.cancelled() => break,
+                value = rx.recv() => {
+                    match value {
+                        None => break,
+                        Some(data) => {
+                            let copies = data.copies(destinations.len());
+                            join_all(
+                                destinations
+                                    .iter()
+                                    .zip(copies)
+                                    .map(|(destination, data)| destination.push(data)),
+                            )
+                            .await;
+                        },
+                    }
+                },
+            }
+        }
+        // Closes the queues of all destinations
+        destinations.clear();
+    };
+    tokio::join!(distribute, join_all(tasks));
+    tracing::trace!("fan_out() end");
+}
+
+/// Starts emitting to all destinations of a comma separated URL list, each one with its own queue
+pub fn start(
+    urls: &str,
+    cancellation_token: CancellationToken,
+    rx: Receiver<EmitterData>,
+) -> Result<EmitterTask, String> {
+    let framing = Framing::from_env()?;
+
+    let urls: Vec<&str> = urls
+        .split(',')
+        .map(str::trim)
+        .filter(|url| !url.is_empty())
+        .collect();
+    match urls.as_slice() {
+        [] => Err("No url given".to_string()),
+        [url] => destination(url, framing, cancellation_token, rx),
+        urls => {
+            let overflow = Overflow::from_env()?;
+            let mut destinations = Vec::with_capacity(urls.len());
+            let mut tasks = Vec::with_capacity(urls.len());
+            for url in urls {
+                let (tx, destination_rx) = tokio::sync::mpsc::channel(QUEUE_SIZE);
+                tasks.push(
+                    destination(url, framing, cancellation_token.clone(), destination_rx)
+                        .map_err(|err| format!("{err} (\"{url}\")"))?,
+                );
+                destinations.push(Destination::new(url, tx, overflow));
+            }
+
+            Ok(Box::pin(fan_out(
+                destinations,
+                tasks,
+                cancellation_token,
+                rx,
+            )))
+        }
+    }
+}
+
+#[cfg(all(test, feature = "tls"))]
+mod tests {
+    use super::{Framing, SocketSender};
+    use crate::tls;
+    use std::fs::File;
+    use std::io::BufReader;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+    use tokio::task::JoinHandle;
+    use tokio_rustls::rustls::ServerConfig;
+    use tokio_rustls::TlsAcceptor;
+
+    fn test_file(name: &str) -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/data")
+            .join(name)
+    }
+
+    /// Local TLS listener with a certificate for `localhost` issued by the test CA, reading
+    /// the given number of bytes from the first connection
+    async fn listen(len: usize) -> (u16, JoinHandle<std::io::Result<Vec<u8>>>) {
+        let certs = rustls_pemfile::certs(&mut BufReader::new(
+            File::open(test_file("server.pem")).expect("open server certificate"),
+        ))
+        .collect::<Result<Vec<_>, _>>()
+        .expect("read server certificate");
+        let key = rustls_pemfile::private_key(&mut BufReader::new(
+            File::open(test_file("server.key")).expect("open server key"),
+        ))
+        .expect("read server key")
+        .expect("server key");
+        let config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .expect("server config");
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind");
+        let port = listener.local_addr().expect("local address").port();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await?;
+            let mut stream = acceptor.accept(stream).await?;
+            let mut received = vec![0; len];
+            stream.read_exact(&mut received).await?;
+            Ok(received)
+        });
+        (port, server)
+    }
+
+    fn sender(port: u16, ca: &str) -> SocketSender {
+        let connector = tls::connector(Some(&test_file(ca)), None, None).expect("connector");
+        SocketSender::tls("localhost", port, connector, Framing::OctetCounting).expect("TLS sender")
+    }
+
+    #[tokio::test]
+    async fn tls_sends_octet_counted_frames() {
+        let expected = b"25 <14>1 - first\nsecond line20 <14>1 - another line".as_slice();
+        let (port, server) = listen(expected.len()).await;
+        let mut sender = sender(port, "ca.pem");
+
+        sender
+            .try_send(b"<14>1 - first\nsecond line\n")
+            .await
+            .expect("send first message");
+        sender
+            .try_send(b"<14>1 - another line\n")
+            .await
+            .expect("send second message");
+
+        let received = server.await.expect("server task").expect("receive");
+        assert_eq!(received, expected);
+    }
+
+    #[tokio::test]
+    async fn tls_rejects_server_of_unknown_ca() {
+        let (port, server) = listen(1).await;
+        let mut sender = sender(port, "other-ca.pem");
+
+        let err = sender
+            .try_send(b"<14>1 - message\n")
+            .await
+            .expect_err("handshake with untrusted server");
+        assert!(err.starts_with("Error during TLS handshake"), "{err}");
+        assert!(server.await.expect("server task").is_err());
     }
 }
@@ -0,0 +1,186 @@
+use crate::container_logs;
+use crate::filter::{Container, Filter};
+use crate::router::{self, Router};
+use crate::syslog::{Severity, StructuredData};
+use crate::{helpers, EmitterData};
+use bollard::models::EventMessage;
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+
+/// Container lifecycle events emitted as syslog messages
+pub const LIFECYCLE_EVENTS: [&str; 8] = [
+    "create",
+    "start",
+    "die",
+    "kill",
+    "oom",
+    "restart",
+    "health_status",
+    "destroy",
+];
+
+/// Name of the structured data element describing the event
+const EVENT_SD_NAME: &str = "event";
+
+fn describe(action: &str, attributes: &HashMap<String, String>) -> (Severity, String) {
+    match action {
+        "create" => (Severity::Informational, "Container created".to_string()),
+        "start" => (Severity::Notice, "Container started".to_string()),
+        "die" => match exit_code(attributes) {
+            None | Some("0") => (Severity::Notice, "Container exited".to_string()),
+            Some(exit_code) => (
+                Severity::Error,
+                format!("Container died with exit code {exit_code}"),
+            ),
+        },
+        "kill" => (
+            Severity::Notice,
+            attributes.get("signal").map_or_else(
+                || "Container killed".to_string(),
+                |signal| format!("Container killed with signal {signal}"),
+            ),
+        ),
+        "oom" => (
+            Severity::Critical,
+            "Container ran out of memory".to_string(),
+        ),
+        "restart" => (Severity::Warning, "Container restarted".to_string()),
+        "destroy" => (Severity::Informational, "Container removed".to_string()),
+        // Podman reports the health status as attribute instead of as part of the action
+        "health_status" => describe_health(attributes.get("health_status").map(String::as_str)),
+        action => action.strip_prefix("health_status:").map_or_else(
+            || (Severity::Informational, format!("Container event {action}")),
+            |status| describe_health(Some(status.trim())),
+        ),
+    }
+}
+
+fn describe_health(status: Option<&str>) -> (Severity, String) {
+    match status {
+        Some("unhealthy") => (Severity::Error, "Container became unhealthy".to_string()),
+        Some(status) => (
+            Severity::Informational,
+            format!("Container health status is {status}"),
+        ),
+        None => (
+            Severity::Informational,
+            "Container health status changed".to_string(),
+        ),
+    }
+}
+
+/// Exit code of Docker or Podman `die` events
+fn exit_code(attributes: &HashMap<String, String>) -> Option<&str> {
+    attributes
+        .get("exitCode")
+        .or_else(|| attributes.get("containerExitCode"))
+        .map(String::as_str)
+}
+
+pub fn event_time(event: &EventMessage) -> DateTime<Utc> {
+    event
+        .time_nano
+        .map(DateTime::from_timestamp_nanos)
+        .or_else(|| {
+            event
+                .time
+                .and_then(|time| DateTime::from_timestamp(time, 0))
+        })
+        .unwrap_or_else(Utc::now)
+}
+
+/// Container events attaching or detaching collectors
+pub const ATTACHMENT_EVENTS: [&str; 7] = [
+    "start", "unpause", "rename", "update", "die", "destroy", "pause",
+];
+
+/// Change of the collector attachment caused by an event
+pub enum Attachment {
+    Attach(String),
+    Detach(String),
+    /// Detaches the collector and drops the checkpoint of the removed container
+    Destroy(String),
+}
+
+impl Attachment {
+    pub fn from_event(event: EventMessage) -> Option<Self> {
+        let container_id = event.actor.and_then(|actor| actor.id)?;
+        match event.action.as_deref()? {
+            // Renamed or updated containers may get selected by the filter only now, while
+            // their running collectors pick up the changes on their own
+            "start" | "unpause" | "rename" | "update" => Some(Self::Attach(container_id)),
+            "die" | "pause" => Some(Self::Detach(container_id)),
+            "destroy" => Some(Self::Destroy(container_id)),
+            _ => None,
+        }
+    }
+}
+
+/// Formats a container lifecycle event and queues it to the destinations of the container
+pub async fn emit(event: &EventMessage, hostname: &str, router: &Router, filter: &Filter) {
+    let (Some(action), Some(actor)) = (event.action.as_deref(), event.actor.as_ref()) else {
+        return;
+    };
+    let Some(container_id) = actor.id.as_deref() else {
+        return;
+    };
+    // Other events are subscribed to for keeping the container metadata up to date
+    let base_action = action.split_once(':').map_or(action, |(action, _)| action);
+    if !LIFECYCLE_EVENTS.contains(&base_action) {
+        return;
+    }
+    let empty = HashMap::new();
+    // Contains the container labels as well as the name and image
+    let attributes = actor.attributes.as_ref().unwrap_or(&empty);
+    let name = attributes.get("name").map(String::as_str);
+    let image = attributes.get("image").map(String::as_str);
+
+    let container = Container {
+        name,
+        image,
+        project: attributes
+            .get("com.docker.compose.project")
+            .map(String::as_str),
+        labels: attributes,
+        enabled: attributes
+            .get("de.hammer065.docker-log-emitter.enabled")
+            .map(|v| helpers::bool_from_str(v)),
+    };
+    if !filter.is_selected(&container) {
+        return;
+    }
+
+    let (severity, msg) = describe(action, attributes);
+    let action = base_action;
+    let ts = event_time(event);
+
+    let mut structured_data = StructuredData::default();
+    structured_data.add(
+        &container_logs::sd_id(EVENT_SD_NAME),
+        [
+            ("action", Some(action)),
+            ("id", Some(container_id)),
+            ("name", name),
+            ("image", image),
+            ("exit_code", exit_code(attributes)),
+            ("signal", attributes.get("signal").map(String::as_str)),
+            ("pod_id", attributes.get("podId").map(String::as_str)),
+        ]
+        .into_iter()
+        .filter_map(|(name, value)| Some((name, value?))),
+    );
+
+    let formatter = container_logs::get_formatter(
+        container_logs::facility(attributes, container_id),
+        hostname,
+        None,
+        Some(action),
+    );
+    let data = formatter.format(msg.as_bytes(), name, severity, &ts, &structured_data);
+
+    router::send(
+        &router.route(attributes, container_id),
+        EmitterData::new(data, None),
+    )
+    .await;
+}
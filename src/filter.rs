@@ -0,0 +1,222 @@
+use crate::helpers;
+use regex::Regex;
+use std::collections::HashMap;
+
+/// Container name, image reference or Compose project patterns, either globs or `/regex/`
+struct Patterns(Vec<Regex>);
+
+fn glob(s: &str) -> String {
+    let mut regex = String::from("^");
+    for c in s.chars() {
+        match c {
+            '*' => regex.push_str(".*"),
+            '?' => regex.push('.'),
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex.push('$');
+    regex
+}
+
+/// Splits comma separated patterns, keeping the commas of regexes like `/^a{1,3}$/`, which
+/// end at a slash followed by a comma or the end of the value
+fn split_patterns(value: &str) -> Vec<&str> {
+    let mut patterns = Vec::new();
+    let mut rest = value;
+    loop {
+        let pattern = rest.trim_start();
+        let regex_end = pattern
+            .strip_prefix('/')
+            .and_then(|regex| {
+                regex.match_indices('/').map(|(i, _)| i + 2).find(|&end| {
+                    let after = pattern[end..].trim_start();
+                    !pattern[..end - 1].ends_with('\\')
+                        && (after.is_empty() || after.starts_with(','))
+                })
+            })
+            .unwrap_or(0);
+        let Some(i) = pattern[regex_end..].find(',') else {
+            patterns.push(pattern);
+            return patterns;
+        };
+        patterns.push(&pattern[..regex_end + i]);
+        rest = &pattern[regex_end + i + 1..];
+    }
+}
+
+impl Patterns {
+    fn from_env(name: &str) -> Result<Option<Self>, String> {
+        let Ok(value) = std::env::var(name) else {
+            return Ok(None);
+        };
+        let patterns = split_patterns(&value)
+            .into_iter()
+            .map(str::trim)
+            .filter(|pattern| !pattern.is_empty())
+            .map(|pattern| {
+                let regex = pattern
+                    .strip_prefix('/')
+                    .and_then(|p| p.strip_suffix('/'))
+                    .map_or_else(|| glob(pattern), String::from);
+                Regex::new(&regex)
+                    .map_err(|err| format!("Invalid {name} pattern \"{pattern}\": {err}"))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok((!patterns.is_empty()).then_some(Self(patterns)))
+    }
+
+    fn is_match(&self, value: Option<&str>) -> bool {
+        value.is_some_and(|value| self.0.iter().any(|regex| regex.is_match(value)))
+    }
+}
+
+enum Selector {
+    Equals(String, String),
+    NotEquals(String, String),
+    Exists(String),
+}
+
+/// Label selector expressions which all have to match
+struct Selectors(Vec<Selector>);
+
+impl Selectors {
+    fn from_env(name: &str) -> Result<Option<Self>, String> {
+        let Ok(value) = std::env::var(name) else {
+            return Ok(None);
+        };
+        let selectors = value
+            .split(',')
+            .map(str::trim)
+            .filter(|selector| !selector.is_empty())
+            .map(|selector| {
+                if let Some((key, value)) = selector.split_once("!=") {
+                    Ok(Selector::NotEquals(key.trim().into(), value.trim().into()))
+                } else if let Some((key, value)) = selector.split_once('=') {
+                    Ok(Selector::Equals(key.trim().into(), value.trim().into()))
+                } else if let Some(key) = selector.strip_suffix(" exists") {
+                    Ok(Selector::Exists(key.trim().into()))
+                } else {
+                    Err(format!("Invalid {name} selector \"{selector}\""))
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok((!selectors.is_empty()).then_some(Self(selectors)))
+    }
+
+    fn is_match(&self, labels: &HashMap<String, String>) -> bool {
+        self.0.iter().all(|selector| match selector {
+            Selector::Equals(key, value) => labels.get(key) == Some(value),
+            Selector::NotEquals(key, value) => labels.get(key) != Some(value),
+            Selector::Exists(key) => labels.contains_key(key),
+        })
+    }
+}
+
+struct Rules {
+    names: Option<Patterns>,
+    images: Option<Patterns>,
+    projects: Option<Patterns>,
+    labels: Option<Selectors>,
+}
+
+impl Rules {
+    fn from_env(prefix: &str) -> Result<Self, String> {
+        Ok(Self {
+            names: Patterns::from_env(&format!("{prefix}_NAMES"))?,
+            images: Patterns::from_env(&format!("{prefix}_IMAGES"))?,
+            projects: Patterns::from_env(&format!("{prefix}_PROJECTS"))?,
+            labels: Selectors::from_env(&format!("{prefix}_LABELS"))?,
+        })
+    }
+
+    /// Results of all configured rules
+    fn matches(&self, container: &Container) -> Vec<bool> {
+        [
+            self.names.as_ref().map(|p| p.is_match(container.name)),
+            self.images.as_ref().map(|p| p.is_match(container.image)),
+            self.projects
+                .as_ref()
+                .map(|p| p.is_match(container.project)),
+            self.labels.as_ref().map(|s| s.is_match(container.labels)),
+        ]
+        .into_iter()
+        .flatten()
+        .collect()
+    }
+}
+
+/// Properties of a container selectable by the filter rules
+pub struct Container<'a> {
+    pub name: Option<&'a str>,
+    pub image: Option<&'a str>,
+    pub project: Option<&'a str>,
+    pub labels: &'a HashMap<String, String>,
+    /// Value of the `enabled` label, overriding all rules
+    pub enabled: Option<bool>,
+}
+
+/// Global container selection configured by the `INCLUDE_*` and `EXCLUDE_*` environment variables
+pub struct Filter {
+    include: Rules,
+    exclude: Rules,
+    opt_in: bool,
+}
+
+impl Filter {
+    pub fn from_env() -> Result<Self, String> {
+        Ok(Self {
+            include: Rules::from_env("INCLUDE")?,
+            exclude: Rules::from_env("EXCLUDE")?,
+            opt_in: std::env::var("OPT_IN").is_ok_and(|v| helpers::bool_from_str(&v)),
+        })
+    }
+
+    /// Checks whether the logs of the container should be collected. Containers have to match
+    /// all configured include rules and none of the exclude rules.
+    pub fn is_selected(&self, container: &Container) -> bool {
+        if let Some(enabled) = container.enabled {
+            return enabled;
+        }
+        !self.opt_in
+            && self.include.matches(container).into_iter().all(|m| m)
+            && !self.exclude.matches(container).into_iter().any(|m| m)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::split_patterns;
+
+    fn split(value: &str) -> Vec<&str> {
+        split_patterns(value).into_iter().map(str::trim).collect()
+    }
+
+    #[test]
+    fn splits_globs_at_commas() {
+        assert_eq!(split("web-*, db ,cache"), ["web-*", "db", "cache"]);
+        assert_eq!(split("web"), ["web"]);
+        assert_eq!(split(""), [""]);
+    }
+
+    #[test]
+    fn keeps_commas_inside_regexes() {
+        assert_eq!(
+            split(r"/^web-\d{1,3}$/, db, /^(a|b),c$/"),
+            [r"/^web-\d{1,3}$/", "db", "/^(a|b),c$/"]
+        );
+        assert_eq!(split("/a,b/ ,c"), ["/a,b/", "c"]);
+    }
+
+    #[test]
+    fn keeps_escaped_slashes_inside_regexes() {
+        assert_eq!(split(r"/^a\/,b$/,c"), [r"/^a\/,b$/", "c"]);
+        assert_eq!(split(r"/a\/,c/"), [r"/a\/,c/"]);
+    }
+
+    #[test]
+    fn splits_unterminated_regexes_at_commas() {
+        assert_eq!(split("/web,db"), ["/web", "db"]);
+    }
+}
@@ -1,32 +1,99 @@
+use ack::Ack;
+use audit::Audit;
+use bollard::container::ListContainersOptions;
 use bollard::system::EventsOptions;
 use bollard::Docker;
+use checkpoints::Checkpoints;
+use events::Attachment;
+use filter::Filter;
 use futures_util::StreamExt;
 use lazy_static::lazy_static;
 #[cfg(all(target_os = "linux", feature = "systemd"))]
 use libsystemd::daemon::NotifyState;
+use registry::{Collector, Registry};
+use router::Router;
+use runtime::{Endpoint, Runtime};
 use std::collections::HashMap;
 use std::path::PathBuf;
+use std::sync::{Arc, Once};
 use std::time::Duration;
-use tokio::sync::mpsc::Receiver;
 use tokio_util::sync::CancellationToken;
 use tokio_util::task::TaskTracker;
 
+mod ack;
+mod audit;
+mod checkpoints;
 mod container_logs;
 mod emitter;
+mod events;
+mod filter;
 mod helpers;
 mod logging;
+mod metadata;
+mod multiline;
+mod parser;
+mod partial;
+mod registry;
+mod router;
+mod runtime;
+mod severity;
+#[cfg(feature = "spool")]
+mod spool;
 mod syslog;
 #[cfg(all(feature = "systemd", target_os = "linux"))]
 mod systemd;
+#[cfg(feature = "tls")]
+mod tls;
 
-pub type EmitterData = Vec<u8>;
+/// Formatted syslog message queued for the emitters
+#[derive(Clone)]
+pub struct EmitterData {
+    pub bytes: Vec<u8>,
+    /// Acknowledgement of the emission, if anything depends on it
+    pub ack: Option<Ack>,
+}
+
+impl EmitterData {
+    #[must_use]
+    pub const fn new(bytes: Vec<u8>, ack: Option<Ack>) -> Self {
+        Self { bytes, ack }
+    }
+
+    /// Copies of the message for the given number of queues. The original goes into the last
+    /// one, as dropping it unacknowledged would cancel the acknowledgement of all copies.
+    #[must_use]
+    pub fn copies(self, count: usize) -> Vec<Self> {
+        let mut copies = Vec::with_capacity(count);
+        if count > 0 {
+            copies.extend(std::iter::repeat_with(|| self.clone()).take(count - 1));
+            copies.push(self);
+        }
+        copies
+    }
+
+    /// Acknowledges the emission of this copy of the message
+    pub fn emitted(self) {
+        if let Some(ack) = self.ack {
+            ack.emitted();
+        }
+    }
+}
 
 // Constants
 lazy_static! {
+    static ref EMIT_EVENTS: bool =
+        std::env::var("EMIT_EVENTS").is_ok_and(|v| helpers::bool_from_str(&v));
     static ref EVENT_FILTER: HashMap<&'static str, Vec<&'static str>> = {
         let mut event_filter = HashMap::with_capacity(2);
         event_filter.insert("type", vec!["container"]);
-        event_filter.insert("event", vec!["start"]);
+        let mut actions = metadata::EVENTS.to_vec();
+        actions.extend(events::ATTACHMENT_EVENTS);
+        if *EMIT_EVENTS {
+            actions.extend(events::LIFECYCLE_EVENTS);
+        }
+        actions.sort_unstable();
+        actions.dedup();
+        event_filter.insert("event", actions);
 
         event_filter
     };
@@ -38,6 +105,8 @@ lazy_static! {
 
 const ONE_SECOND: Duration = Duration::from_secs(1);
 
+static READY: Once = Once::new();
+
 // Initializer functions
 #[inline]
 fn ctrl_c_handler(token: CancellationToken, tracker: &TaskTracker) {
@@ -92,22 +161,48 @@ impl Drop for PidFile {
 }
 
 #[inline]
-fn emitter(
-    rx: Receiver<EmitterData>,
-    cancellation_token: CancellationToken,
+fn router(cancellation_token: &CancellationToken, tracker: &TaskTracker) -> Option<Arc<Router>> {
+    match Router::start(cancellation_token, tracker) {
+        Ok(router) => Some(Arc::new(router)),
+        Err(err) => {
+            tracing::error!("Error starting emitter: {err}");
+            None
+        }
+    }
+}
+
+#[inline]
+fn endpoints() -> Option<Vec<Arc<Endpoint>>> {
+    match Endpoint::from_env(HOSTNAME.as_str()) {
+        Ok(endpoints) => Some(endpoints.into_iter().map(Arc::new).collect()),
+        Err(err) => {
+            tracing::error!("Error parsing Docker endpoints: {err}");
+            None
+        }
+    }
+}
+
+#[inline]
+fn audit_events(
+    router: &Arc<Router>,
+    endpoints: &[Arc<Endpoint>],
+    cancellation_token: &CancellationToken,
     tracker: &TaskTracker,
 ) -> bool {
-    let Ok(url) = std::env::var("EMITTER_URL") else {
-        tracing::error!("Could not get EMITTER_URL environment variable");
-        return false;
-    };
-
-    match emitter::start(url, cancellation_token, rx) {
-        Ok(task) => {
-            tracker.spawn(task);
+    match Audit::from_env() {
+        Ok(Some(audit)) => {
+            for endpoint in endpoints {
+                tracker.spawn(audit::forward(
+                    audit.clone(),
+                    router.clone(),
+                    endpoint.clone(),
+                    cancellation_token.clone(),
+                ));
+            }
         }
+        Ok(None) => {}
         Err(err) => {
-            tracing::error!("Error starting emitter: {err}");
+            tracing::error!("Error parsing audit events: {err}");
             return false;
         }
     }
@@ -115,7 +210,63 @@ fn emitter(
     true
 }
 
+#[inline]
+fn persisted_checkpoints(
+    cancellation_token: CancellationToken,
+    tracker: &TaskTracker,
+) -> Arc<Checkpoints> {
+    let checkpoints = Arc::new(Checkpoints::from_env());
+    tracker.spawn(checkpoints::persist(
+        checkpoints.clone(),
+        cancellation_token,
+    ));
+
+    checkpoints
+}
+
+#[inline]
+fn container_filter() -> Option<Arc<Filter>> {
+    match Filter::from_env() {
+        Ok(filter) => Some(Arc::new(filter)),
+        Err(err) => {
+            tracing::error!("Error parsing container filter: {err}");
+            None
+        }
+    }
+}
+
 // Helper functions
+async fn retain_checkpoints(
+    docker: &Docker,
+    scope: &str,
+    checkpoints: &Checkpoints,
+) -> Result<(), bollard::errors::Error> {
+    let containers = docker
+        .list_containers(Some(ListContainersOptions::<String> {
+            all: true,
+            ..Default::default()
+        }))
+        .await?;
+    checkpoints.retain(
+        scope,
+        &containers.into_iter().filter_map(|c| c.id).collect(),
+    );
+
+    Ok(())
+}
+
+/// IDs of the running containers, dropping the checkpoints of removed ones
+async fn running_containers(
+    docker: &Docker,
+    scope: &str,
+    checkpoints: &Checkpoints,
+) -> Result<Vec<String>, bollard::errors::Error> {
+    retain_checkpoints(docker, scope, checkpoints).await?;
+    let containers = docker.list_containers::<String>(None).await?;
+
+    Ok(containers.into_iter().filter_map(|c| c.id).collect())
+}
+
 async fn stop_execution(token: &CancellationToken, tracker: &TaskTracker) {
     token.cancel();
     tracker.close();
@@ -127,69 +278,111 @@ async fn stop_execution(token: &CancellationToken, tracker: &TaskTracker) {
     }
 }
 
-// Main
-#[tokio::main]
-async fn main() {
-    logging::init();
-    tracing::info!("Starting application...");
-    let mut is_starting_up = true;
-
-    let pid_file = PidFile::new();
-
-    let global_tracker = TaskTracker::new();
+/// Signals readiness once the containers of the first endpoint are attached
+fn notify_ready() {
+    READY.call_once(|| {
+        #[cfg(all(target_os = "linux", feature = "systemd"))]
+        systemd::notify(&NotifyState::Ready);
+        tracing::info!("Application started successfully!");
+    });
+}
 
-    let ctrl_c_token = CancellationToken::new();
-    ctrl_c_handler(ctrl_c_token.clone(), &global_tracker);
+/// Collects the logs of the registered container, unregistering the collector once it ended
+/// and dropping the checkpoint of removed containers
+async fn run_collector(
+    collector: Collector,
+    registry: Arc<Registry>,
+    runtime: Arc<Runtime>,
+    router: Arc<Router>,
+    cancellation_token: CancellationToken,
+    checkpoints: Arc<Checkpoints>,
+    filter: Arc<Filter>,
+) {
+    container_logs::collect(
+        &collector,
+        runtime.clone(),
+        router,
+        cancellation_token,
+        checkpoints.clone(),
+        filter,
+    )
+    .await;
+    if registry.unregister(&collector) {
+        checkpoints.remove(runtime.endpoint.checkpoint_scope(), &collector.container_id);
+    }
+}
 
-    let (log_tx, log_rx) = tokio::sync::mpsc::channel::<EmitterData>(1024);
-    if !emitter(log_rx, ctrl_c_token.clone(), &global_tracker) {
-        return;
+/// Detaches the collector of the removed container and drops its checkpoint, which a collector
+/// still draining does once it ended
+fn destroy(registry: &Registry, checkpoints: &Checkpoints, scope: &str, container_id: &str) {
+    if !registry.destroy(container_id) {
+        checkpoints.remove(scope, container_id);
     }
+}
 
-    'main_loop: loop {
+/// Collects the logs of the running and starting containers of the endpoint until cancelled,
+/// reconnecting on errors
+async fn discover(
+    endpoint: Arc<Endpoint>,
+    router: Arc<Router>,
+    filter: Arc<Filter>,
+    checkpoints: Arc<Checkpoints>,
+    ctrl_c_token: CancellationToken,
+) {
+    tracing::trace!("discover(endpoint = \"{}\") start", endpoint.name);
+    'discover_loop: loop {
         if ctrl_c_token.is_cancelled() {
             break;
         }
 
-        let cancellation_token = CancellationToken::new();
-        let tracker = TaskTracker::new();
-
-        let docker = match Docker::connect_with_local_defaults() {
-            Ok(docker) => docker,
+        let runtime = match Runtime::connect(&endpoint).await {
+            Ok(runtime) => Arc::new(runtime),
             Err(err) => {
-                tracing::warn!("Could not connect to Docker daemon: {err}");
+                tracing::warn!(
+                    "Could not connect to Docker daemon \"{}\": {err}",
+                    endpoint.name
+                );
                 tokio::time::sleep(ONE_SECOND).await;
                 continue;
             }
         };
+        let docker = &runtime.docker;
 
-        let containers = match docker.list_containers::<String>(None).await {
-            Ok(containers) => containers,
-            Err(err) => {
-                tracing::warn!("Could not fetch list of containers: {err}");
-                tokio::time::sleep(ONE_SECOND).await;
-                continue;
-            }
-        };
-        for c in containers {
-            let Some(container_id) = c.id else {
-                continue;
+        // All collectors share the client of the endpoint
+        let cancellation_token = CancellationToken::new();
+        let tracker = TaskTracker::new();
+        let registry = Arc::new(Registry::default());
+        let collect = |container_id: String| {
+            let Some(collector) = registry.register(&container_id) else {
+                return;
             };
-
-            tracker.spawn(container_logs::collect(
-                container_id,
-                log_tx.clone(),
+            tracker.spawn(run_collector(
+                collector,
+                registry.clone(),
+                runtime.clone(),
+                router.clone(),
                 cancellation_token.clone(),
-                HOSTNAME.as_str(),
+                checkpoints.clone(),
+                filter.clone(),
             ));
-        }
+        };
 
-        if is_starting_up {
-            #[cfg(all(target_os = "linux", feature = "systemd"))]
-            systemd::notify(&NotifyState::Ready);
-            tracing::info!("Application started successfully!");
-            is_starting_up = false;
+        let containers =
+            match running_containers(docker, endpoint.checkpoint_scope(), &checkpoints).await {
+                Ok(containers) => containers,
+                Err(err) => {
+                    tracing::warn!(
+                        "Could not fetch list of containers of \"{}\": {err}",
+                        endpoint.name
+                    );
+                    tokio::time::sleep(ONE_SECOND).await;
+                    continue;
+                }
+            };
+        for container_id in containers {
+            collect(container_id);
         }
+        notify_ready();
 
         let events = &mut docker.events(Some(EventsOptions {
             since: None,
@@ -201,23 +394,28 @@ async fn main() {
             tokio::select! {
                 biased;
                 () = ctrl_c_token.cancelled() => {
-                    #[cfg(all(target_os = "linux", feature = "systemd"))]
-                    systemd::notify(&NotifyState::Stopping);
-                    tracing::info!("Shutting down...");
                     stop_execution(&cancellation_token, &tracker).await;
-                    break 'main_loop;
+                    break 'discover_loop;
                 },
                 event = events.next() => {
                     match event {
                         Some(Ok(event)) => {
-                            let Some(container_id) = event.actor.and_then(|actor| actor.id) else {
-                                continue;
-                            };
-
-                            tracker.spawn(container_logs::collect(container_id, log_tx.clone(), cancellation_token.clone(), HOSTNAME.as_str()));
+                            runtime.handle(&event).await;
+                            if *EMIT_EVENTS {
+                                events::emit(&event, &endpoint.hostname, &router, &filter).await;
+                            }
+                            match Attachment::from_event(event) {
+                                Some(Attachment::Attach(container_id)) => collect(container_id),
+                                Some(Attachment::Detach(container_id)) => registry.detach(&container_id),
+                                Some(Attachment::Destroy(container_id)) => destroy(&registry, &checkpoints, endpoint.checkpoint_scope(), &container_id),
+                                None => {}
+                            }
                         },
                         Some(Err(err)) => {
-                            tracing::warn!("Error while reading event stream: {err}");
+                            tracing::warn!(
+                                "Error while reading event stream of \"{}\": {err}",
+                                endpoint.name
+                            );
                             stop_execution(&cancellation_token, &tracker).await;
                             break;
                         },
@@ -230,8 +428,62 @@ async fn main() {
             }
         }
     }
+    tracing::trace!("discover(endpoint = \"{}\") end", endpoint.name);
+}
+
+// Main
+#[tokio::main]
+async fn main() {
+    logging::init();
+    tracing::info!("Starting application...");
+
+    let pid_file = PidFile::new();
+
+    let global_tracker = TaskTracker::new();
+
+    let ctrl_c_token = CancellationToken::new();
+    ctrl_c_handler(ctrl_c_token.clone(), &global_tracker);
+
+    let Some(filter) = container_filter() else {
+        return;
+    };
+
+    if let Err(err) = container_logs::check_env() {
+        tracing::error!("Error parsing log settings: {err}");
+        return;
+    }
+
+    let Some(endpoints) = endpoints() else {
+        return;
+    };
+
+    let Some(router) = router(&ctrl_c_token, &global_tracker) else {
+        return;
+    };
+
+    if !audit_events(&router, &endpoints, &ctrl_c_token, &global_tracker) {
+        return;
+    }
+
+    let checkpoints = persisted_checkpoints(ctrl_c_token.clone(), &global_tracker);
+
+    for endpoint in endpoints {
+        global_tracker.spawn(discover(
+            endpoint,
+            router.clone(),
+            filter.clone(),
+            checkpoints.clone(),
+            ctrl_c_token.clone(),
+        ));
+    }
+
+    ctrl_c_token.cancelled().await;
+    #[cfg(all(target_os = "linux", feature = "systemd"))]
+    systemd::notify(&NotifyState::Stopping);
+    tracing::info!("Shutting down...");
 
     stop_execution(&ctrl_c_token, &global_tracker).await;
+    checkpoints.save().await;
     drop(pid_file);
     tracing::info!("Completed shutdown. Bye!");
 }
@@ -0,0 +1,88 @@
+use bollard::models::{ContainerInspectResponse, EventMessage};
+use bollard::Docker;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use tokio::sync::watch;
+
+/// Container events keeping the cached metadata up to date
+pub const EVENTS: [&str; 4] = ["start", "destroy", "rename", "update"];
+
+type Info = Arc<ContainerInspectResponse>;
+
+/// Container info of an engine cached per container ID, shared by all of its collectors
+pub struct Metadata {
+    docker: Docker,
+    containers: Mutex<HashMap<String, watch::Sender<Info>>>,
+}
+
+impl Metadata {
+    pub fn new(docker: Docker) -> Self {
+        Self {
+            docker,
+            containers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, watch::Sender<Info>>> {
+        self.containers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Fetches the container info, notifying the receivers of the previous one
+    async fn inspect(
+        &self,
+        container_id: &str,
+    ) -> Result<watch::Receiver<Info>, bollard::errors::Error> {
+        let info = Arc::new(self.docker.inspect_container(container_id, None).await?);
+        let rx = self
+            .lock()
+            .entry(container_id.to_string())
+            .and_modify(|tx| {
+                tx.send_replace(info.clone());
+            })
+            .or_insert_with(|| watch::channel(info).0)
+            .subscribe();
+        Ok(rx)
+    }
+
+    /// Receiver of the cached container info and its updates, fetched from the engine on
+    /// first use
+    pub async fn get(
+        &self,
+        container_id: &str,
+    ) -> Result<watch::Receiver<Info>, bollard::errors::Error> {
+        let cached = self.lock().get(container_id).map(watch::Sender::subscribe);
+        match cached {
+            Some(rx) => Ok(rx),
+            None => self.inspect(container_id).await,
+        }
+    }
+
+    /// Updates the cache on container events. Started, renamed and updated containers get
+    /// refetched, notifying their collectors, while destroyed ones get dropped.
+    pub async fn handle(&self, event: &EventMessage) {
+        let (Some(action), Some(container_id)) = (
+            event.action.as_deref(),
+            event.actor.as_ref().and_then(|actor| actor.id.as_deref()),
+        ) else {
+            return;
+        };
+
+        match action {
+            "destroy" => {
+                self.lock().remove(container_id);
+            }
+            "start" | "rename" | "update" => {
+                if !self.lock().contains_key(container_id) {
+                    return;
+                }
+                if let Err(err) = self.inspect(container_id).await {
+                    tracing::warn!("Error refreshing info for container \"{container_id}\": {err}");
+                    self.lock().remove(container_id);
+                }
+            }
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,217 @@
+use chrono::{DateTime, Utc};
+use regex::bytes::Regex;
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::time::Instant;
+
+const LABEL: &str = "de.hammer065.docker-log-emitter.multiline";
+
+const DEFAULT_MAX_LINES: usize = 500;
+const DEFAULT_MAX_BYTES: usize = 64 * 1024;
+const DEFAULT_TIMEOUT_MS: u64 = 1000;
+
+/// Log record consisting of one or more log lines of the same stream
+pub struct Record {
+    pub is_err: bool,
+    /// Timestamp of the first line
+    pub ts: DateTime<Utc>,
+    /// Timestamp of the last line
+    pub last_ts: DateTime<Utc>,
+    pub msg: Vec<u8>,
+    lines: usize,
+}
+
+impl Record {
+    fn new(is_err: bool, ts: DateTime<Utc>, line: &[u8]) -> Self {
+        Self {
+            is_err,
+            ts,
+            last_ts: ts,
+            msg: line.to_vec(),
+            lines: 1,
+        }
+    }
+}
+
+pub struct Pattern {
+    start: Option<Regex>,
+    continuation: Option<Regex>,
+    max_lines: usize,
+    max_bytes: usize,
+    timeout: Duration,
+}
+
+fn regex(s: &str) -> Result<Regex, String> {
+    Regex::new(s).map_err(|err| format!("Invalid multiline pattern \"{s}\": {err}"))
+}
+
+fn label<T: std::str::FromStr>(
+    labels: &HashMap<String, String>,
+    name: &str,
+    default: T,
+) -> Result<T, String>
+where
+    T::Err: std::fmt::Display,
+{
+    labels
+        .get(&format!("{LABEL}.{name}"))
+        .map_or(Ok(default), |v| {
+            v.trim()
+                .parse()
+                .map_err(|err| format!("Invalid {LABEL}.{name} label \"{v}\": {err}"))
+        })
+}
+
+impl Pattern {
+    /// Builds the pattern selected by the container labels, either a preset or custom regexes
+    pub fn from_labels(labels: &HashMap<String, String>) -> Result<Option<Self>, String> {
+        let (start, continuation) = match labels.get(LABEL).map(String::as_str) {
+            None | Some("custom") => (
+                labels
+                    .get(&format!("{LABEL}.start"))
+                    .map(|s| regex(s))
+                    .transpose()?,
+                labels
+                    .get(&format!("{LABEL}.continuation"))
+                    .map(|s| regex(s))
+                    .transpose()?,
+            ),
+            Some("indented") => (None, Some(regex(r"^[ \t]")?)),
+            Some("java") => (
+                None,
+                Some(regex(r"^([ \t]|Caused by:|Suppressed:|\.\.\. \d+ more)")?),
+            ),
+            Some("python") => (
+                None,
+                Some(regex(
+                    r"^([ \t]|Traceback \(most recent call last\):|During handling of the above exception|The above exception was the direct cause|[\w.]*(Error|Exception|Warning|Exit|Interrupt)(:|$))",
+                )?),
+            ),
+            Some(preset) => return Err(format!("Unknown multiline preset \"{preset}\"")),
+        };
+        if start.is_none() && continuation.is_none() {
+            return Ok(None);
+        }
+
+        Ok(Some(Self {
+            start,
+            continuation,
+            max_lines: label(labels, "max_lines", DEFAULT_MAX_LINES)?,
+            max_bytes: label(labels, "max_bytes", DEFAULT_MAX_BYTES)?,
+            timeout: Duration::from_millis(label(labels, "timeout", DEFAULT_TIMEOUT_MS)?),
+        }))
+    }
+
+    fn is_continuation(&self, line: &[u8]) -> bool {
+        match (self.start.as_ref(), self.continuation.as_ref()) {
+            (Some(start), Some(continuation)) => {
+                !start.is_match(line) && continuation.is_match(line)
+            }
+            (Some(start), None) => !start.is_match(line),
+            (None, Some(continuation)) => continuation.is_match(line),
+            (None, None) => false,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Pending {
+    record: Option<Record>,
+    deadline: Option<Instant>,
+}
+
+/// Aggregates consecutive log lines of a container into records, separately per stream
+pub struct Multiline {
+    pattern: Option<Pattern>,
+    stdout: Pending,
+    stderr: Pending,
+}
+
+impl Multiline {
+    pub fn new(pattern: Option<Pattern>) -> Self {
+        Self {
+            pattern,
+            stdout: Pending::default(),
+            stderr: Pending::default(),
+        }
+    }
+
+    /// Adds a log line, returning the records completed by it
+    pub fn push(&mut self, is_err: bool, ts: DateTime<Utc>, line: &[u8]) -> Vec<Record> {
+        let end = line
+            .iter()
+            .rposition(|b| !matches!(*b, b'\n' | b'\r'))
+            .map_or(0, |i| i + 1);
+        let line = &line[..end];
+
+        let Some(pattern) = self.pattern.as_ref() else {
+            return vec![Record::new(is_err, ts, line)];
+        };
+        let pending = if is_err {
+            &mut self.stderr
+        } else {
+            &mut self.stdout
+        };
+
+        let mut completed = Vec::new();
+        if let Some(record) = pending.record.as_mut() {
+            if pattern.is_continuation(line)
+                && record.lines < pattern.max_lines
+                && record.msg.len() + 1 + line.len() <= pattern.max_bytes
+            {
+                record.msg.push(b'\n');
+                record.msg.extend_from_slice(line);
+                record.lines += 1;
+                record.last_ts = ts;
+                pending.deadline = Some(Instant::now() + pattern.timeout);
+                return completed;
+            }
+            completed.extend(pending.record.take());
+        }
+
+        pending.record = Some(Record::new(is_err, ts, line));
+        pending.deadline = Some(Instant::now() + pattern.timeout);
+        completed
+    }
+
+    /// Point in time the next pending record has to be flushed at
+    pub fn deadline(&self) -> Option<Instant> {
+        match (self.stdout.deadline, self.stderr.deadline) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    /// Timestamp of the earliest line of the pending records
+    pub fn pending_since(&self) -> Option<DateTime<Utc>> {
+        [self.stdout.record.as_ref(), self.stderr.record.as_ref()]
+            .into_iter()
+            .flatten()
+            .map(|record| record.ts)
+            .min()
+    }
+
+    /// Returns all pending records whose flush timeout expired
+    pub fn flush_expired(&mut self) -> Vec<Record> {
+        let now = Instant::now();
+        [&mut self.stdout, &mut self.stderr]
+            .into_iter()
+            .filter(|pending| pending.deadline.is_some_and(|deadline| deadline <= now))
+            .filter_map(|pending| {
+                pending.deadline = None;
+                pending.record.take()
+            })
+            .collect()
+    }
+
+    /// Returns all pending records
+    pub fn flush(&mut self) -> Vec<Record> {
+        [&mut self.stdout, &mut self.stderr]
+            .into_iter()
+            .filter_map(|pending| {
+                pending.deadline = None;
+                pending.record.take()
+            })
+            .collect()
+    }
+}
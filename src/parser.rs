@@ -0,0 +1,334 @@
+use crate::container_logs;
+use crate::syslog::{Severity, StructuredData};
+use chrono::{DateTime, Utc};
+use serde_json::{Map, Value};
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+const LABEL: &str = "de.hammer065.docker-log-emitter.parser";
+/// Name of the structured data element holding the parsed fields
+const SD_NAME: &str = "fields";
+
+const JSON_LEVEL_KEY: &str = "level,severity,log.level";
+const JSON_MESSAGE_KEY: &str = "message,msg";
+const JSON_TIMESTAMP_KEY: &str = "timestamp,time,ts,@timestamp";
+const JSON_LOGGER_KEY: &str = "logger,logger_name,log.logger";
+
+const LOGFMT_LEVEL_KEY: &str = "level,lvl";
+const LOGFMT_MESSAGE_KEY: &str = "msg,message";
+const LOGFMT_TIMESTAMP_KEY: &str = "ts,time,timestamp";
+const LOGFMT_LOGGER_KEY: &str = "logger,caller";
+
+/// Information extracted from a structured log record
+pub struct Parsed<'a> {
+    pub severity: Option<Severity>,
+    pub ts: Option<DateTime<Utc>>,
+    pub msg: Cow<'a, [u8]>,
+    pub structured_data: StructuredData,
+}
+
+/// Comma separated alternatives of dot separated paths to a JSON field
+struct KeyPath(Vec<String>);
+
+impl KeyPath {
+    fn new(s: &str) -> Self {
+        Self(
+            s.split(',')
+                .map(str::trim)
+                .filter(|path| !path.is_empty())
+                .map(String::from)
+                .collect(),
+        )
+    }
+
+    fn take(&self, fields: &mut Map<String, Value>) -> Option<Value> {
+        self.0.iter().find_map(|path| take(fields, path))
+    }
+}
+
+fn take(fields: &mut Map<String, Value>, path: &str) -> Option<Value> {
+    // Flat keys containing dots take precedence over nested objects
+    if let Some(value) = fields.remove(path) {
+        return Some(value);
+    }
+    let (first, rest) = path.split_once('.')?;
+    take(fields.get_mut(first)?.as_object_mut()?, rest)
+}
+
+fn severity(value: &Value) -> Option<Severity> {
+    match value {
+        Value::String(level) => Severity::from_name(level),
+        // Syslog severities or the numeric levels of pino and bunyan
+        Value::Number(level) => match level.as_u64()? {
+            level @ 0..=7 => Severity::from_numerical_code(level),
+            8..=20 => Some(Severity::Debug),
+            21..=30 => Some(Severity::Informational),
+            31..=40 => Some(Severity::Warning),
+            41..=50 => Some(Severity::Error),
+            _ => Some(Severity::Critical),
+        },
+        _ => None,
+    }
+}
+
+fn timestamp(value: &Value) -> Option<DateTime<Utc>> {
+    match value {
+        Value::String(ts) => DateTime::parse_from_rfc3339(ts).ok().map(|ts| ts.to_utc()),
+        Value::Number(ts) => {
+            // UNIX timestamps in seconds with optional fraction, or in milli-, micro- or nanoseconds
+            let ts = ts.to_string();
+            let (secs, fraction) = ts.split_once('.').unwrap_or((&ts, ""));
+            let secs: i64 = secs.parse().ok()?;
+            match secs.unsigned_abs() {
+                0..100_000_000_000 => {
+                    let nanos = format!("{fraction:0<9}");
+                    DateTime::from_timestamp(secs, nanos.get(..9)?.parse().ok()?)
+                }
+                100_000_000_000..100_000_000_000_000 => DateTime::from_timestamp_millis(secs),
+                100_000_000_000_000..100_000_000_000_000_000 => {
+                    DateTime::from_timestamp_micros(secs)
+                }
+                _ => Some(DateTime::from_timestamp_nanos(secs)),
+            }
+        }
+        _ => None,
+    }
+}
+
+fn text(value: Value) -> String {
+    match value {
+        Value::String(s) => s,
+        value => value.to_string(),
+    }
+}
+
+/// Flattens nested objects into parameters with dot separated names
+fn flatten(params: &mut Vec<(String, String)>, prefix: &str, fields: Map<String, Value>) {
+    for (key, value) in fields {
+        let name = if prefix.is_empty() {
+            key
+        } else {
+            format!("{prefix}.{key}")
+        };
+        match value {
+            Value::Object(fields) => flatten(params, &name, fields),
+            value => params.push((name, text(value))),
+        }
+    }
+}
+
+fn parse_json(msg: &[u8]) -> Option<Map<String, Value>> {
+    if msg.trim_ascii_start().first() != Some(&b'{') {
+        return None;
+    }
+    match serde_json::from_slice(msg) {
+        Ok(Value::Object(fields)) => Some(fields),
+        _ => None,
+    }
+}
+
+/// Parses `key=value` pairs with optionally quoted values. Each field needs a value to avoid
+/// misinterpreting plain text log lines.
+fn parse_logfmt(msg: &[u8]) -> Option<Map<String, Value>> {
+    let mut chars = std::str::from_utf8(msg).ok()?.trim().chars().peekable();
+    let mut fields = Map::new();
+    while chars.peek().is_some() {
+        let mut key = String::new();
+        while let Some(c) = chars.next_if(|c| *c != '=') {
+            if c == '"' || c.is_whitespace() {
+                return None;
+            }
+            key.push(c);
+        }
+        if key.is_empty() || chars.next().is_none() {
+            return None;
+        }
+
+        let mut value = String::new();
+        if chars.next_if_eq(&'"').is_some() {
+            loop {
+                match chars.next()? {
+                    '"' => break,
+                    '\\' => match chars.next()? {
+                        'n' => value.push('\n'),
+                        'r' => value.push('\r'),
+                        't' => value.push('\t'),
+                        c => value.push(c),
+                    },
+                    c => value.push(c),
+                }
+            }
+            if chars.peek().is_some_and(|c| !c.is_whitespace()) {
+                return None;
+            }
+        } else {
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                value.push(c);
+            }
+        }
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+
+        fields.insert(key, Value::String(value));
+    }
+
+    (!fields.is_empty()).then_some(fields)
+}
+
+/// Fields to extract from parsed log records
+pub struct Fields {
+    level: KeyPath,
+    message: KeyPath,
+    timestamp: KeyPath,
+    logger: KeyPath,
+    keep_payload: bool,
+}
+
+impl Fields {
+    fn extract<'a>(&self, msg: &'a [u8], mut fields: Map<String, Value>) -> Parsed<'a> {
+        let severity = self.level.take(&mut fields).as_ref().and_then(severity);
+        let ts = self
+            .timestamp
+            .take(&mut fields)
+            .as_ref()
+            .and_then(timestamp);
+        let message = self.message.take(&mut fields).map(text);
+        // Without message field, the payload is the message and carries all fields already
+        let Some(message) = message.filter(|_| !self.keep_payload) else {
+            return Parsed {
+                severity,
+                ts,
+                msg: Cow::Borrowed(msg),
+                structured_data: StructuredData::default(),
+            };
+        };
+
+        let mut params = Vec::new();
+        if let Some(logger) = self.logger.take(&mut fields) {
+            params.push((String::from("logger"), text(logger)));
+        }
+        flatten(&mut params, "", fields);
+        let mut structured_data = StructuredData::default();
+        structured_data.add(&container_logs::sd_id(SD_NAME), params);
+
+        Parsed {
+            severity,
+            ts,
+            msg: Cow::Owned(message.into_bytes()),
+            structured_data,
+        }
+    }
+}
+
+pub enum Parser {
+    Json(Fields),
+    Logfmt(Fields),
+}
+
+fn key_path(labels: &HashMap<String, String>, name: &str, default: &str) -> KeyPath {
+    KeyPath::new(
+        labels
+            .get(&format!("{LABEL}.{name}"))
+            .map_or(default, String::as_str),
+    )
+}
+
+fn fields(
+    labels: &HashMap<String, String>,
+    keep_payload: bool,
+    [level, message, timestamp, logger]: [&str; 4],
+) -> Fields {
+    Fields {
+        level: key_path(labels, "level_key", level),
+        message: key_path(labels, "message_key", message),
+        timestamp: key_path(labels, "timestamp_key", timestamp),
+        logger: key_path(labels, "logger_key", logger),
+        keep_payload,
+    }
+}
+
+impl Parser {
+    /// Builds the parser selected by the container labels. If `keep_payload` is set, the
+    /// message is emitted unchanged instead of moving its fields into structured data.
+    pub fn from_labels(
+        labels: &HashMap<String, String>,
+        keep_payload: bool,
+    ) -> Result<Option<Self>, String> {
+        match labels.get(LABEL).map(|v| v.trim()) {
+            None | Some("" | "none") => Ok(None),
+            Some("json") => Ok(Some(Self::Json(fields(
+                labels,
+                keep_payload,
+                [
+                    JSON_LEVEL_KEY,
+                    JSON_MESSAGE_KEY,
+                    JSON_TIMESTAMP_KEY,
+                    JSON_LOGGER_KEY,
+                ],
+            )))),
+            Some("logfmt") => Ok(Some(Self::Logfmt(fields(
+                labels,
+                keep_payload,
+                [
+                    LOGFMT_LEVEL_KEY,
+                    LOGFMT_MESSAGE_KEY,
+                    LOGFMT_TIMESTAMP_KEY,
+                    LOGFMT_LOGGER_KEY,
+                ],
+            )))),
+            Some(parser) => Err(format!("Unknown parser \"{parser}\"")),
+        }
+    }
+
+    /// Parses a log record, returns `None` if it is not in the expected format
+    pub fn parse<'a>(&self, msg: &'a [u8]) -> Option<Parsed<'a>> {
+        match self {
+            Self::Json(fields) => parse_json(msg).map(|parsed| fields.extract(msg, parsed)),
+            Self::Logfmt(fields) => parse_logfmt(msg).map(|parsed| fields.extract(msg, parsed)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_logfmt;
+    use serde_json::{json, Value};
+
+    fn logfmt(msg: &str) -> Option<Value> {
+        parse_logfmt(msg.as_bytes()).map(Value::Object)
+    }
+
+    #[test]
+    fn parses_plain_and_quoted_values() {
+        assert_eq!(
+            logfmt("level=info msg=\"request done\" path=/api  status=200\n"),
+            Some(json!({
+                "level": "info",
+                "msg": "request done",
+                "path": "/api",
+                "status": "200",
+            }))
+        );
+    }
+
+    #[test]
+    fn unescapes_quoted_values() {
+        assert_eq!(
+            logfmt(r#"msg="say \"hi\"\nbye" empty="""#),
+            Some(json!({ "msg": "say \"hi\"\nbye", "empty": "" }))
+        );
+        assert_eq!(
+            logfmt("empty= next=1"),
+            Some(json!({ "empty": "", "next": "1" }))
+        );
+    }
+
+    #[test]
+    fn rejects_plain_text() {
+        assert_eq!(logfmt("Starting server on port 8080"), None);
+        assert_eq!(logfmt("level=info but then text"), None);
+        assert_eq!(logfmt("msg=\"unterminated"), None);
+        assert_eq!(logfmt("msg=\"quoted\"suffix"), None);
+        assert_eq!(logfmt("=value"), None);
+        assert_eq!(logfmt(""), None);
+    }
+}
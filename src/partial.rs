@@ -0,0 +1,224 @@
+use chrono::{DateTime, Utc};
+use std::borrow::Cow;
+use std::time::Duration;
+use tokio::time::Instant;
+
+const DEFAULT_MAX_SIZE: usize = 1024 * 1024;
+const DEFAULT_TIMEOUT_MS: u64 = 1000;
+
+/// Reassembly limits, configured by the `MAX_LINE_SIZE` and `PARTIAL_TIMEOUT` environment
+/// variables
+#[derive(Clone, Copy)]
+pub struct Limits {
+    max_size: usize,
+    timeout: Duration,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_size: DEFAULT_MAX_SIZE,
+            timeout: Duration::from_millis(DEFAULT_TIMEOUT_MS),
+        }
+    }
+}
+
+impl Limits {
+    pub fn from_env() -> Result<Self, String> {
+        let max_size = std::env::var("MAX_LINE_SIZE").map_or(Ok(DEFAULT_MAX_SIZE), |v| {
+            v.trim()
+                .parse()
+                .map_err(|err| format!("Invalid value for MAX_LINE_SIZE: {err}"))
+        })?;
+        let timeout = std::env::var("PARTIAL_TIMEOUT").map_or(Ok(DEFAULT_TIMEOUT_MS), |v| {
+            v.trim()
+                .parse()
+                .map_err(|err| format!("Invalid value for PARTIAL_TIMEOUT: {err}"))
+        })?;
+
+        Ok(Self {
+            max_size,
+            timeout: Duration::from_millis(timeout),
+        })
+    }
+}
+
+struct Partial {
+    ts: DateTime<Utc>,
+    msg: Vec<u8>,
+    truncated: bool,
+    /// Point in time the incomplete line gets emitted at if no further chunk arrives
+    deadline: Instant,
+}
+
+/// Reassembles log lines the Docker log drivers split into partial messages, separately per stream
+pub struct Partials {
+    limits: Limits,
+    stdout: Option<Partial>,
+    stderr: Option<Partial>,
+}
+
+impl Partials {
+    pub const fn new(limits: Limits) -> Self {
+        Self {
+            limits,
+            stdout: None,
+            stderr: None,
+        }
+    }
+
+    /// Adds a log message chunk, returning the complete log line once its last chunk arrived.
+    /// Partial messages are recognized by their missing trailing newline.
+    pub fn push<'a>(
+        &mut self,
+        is_err: bool,
+        ts: DateTime<Utc>,
+        chunk: &'a [u8],
+    ) -> Option<(DateTime<Utc>, Cow<'a, [u8]>)> {
+        let is_complete = chunk.ends_with(b"\n");
+        let partial = if is_err {
+            &mut self.stderr
+        } else {
+            &mut self.stdout
+        };
+
+        match partial.as_mut() {
+            None if is_complete => Some((ts, Cow::Borrowed(chunk))),
+            None => {
+                *partial = Some(Partial {
+                    ts,
+                    msg: chunk.to_vec(),
+                    truncated: false,
+                    deadline: Instant::now() + self.limits.timeout,
+                });
+                None
+            }
+            Some(buffer) => {
+                let room = self.limits.max_size.saturating_sub(buffer.msg.len());
+                if chunk.len() > room && !buffer.truncated {
+                    tracing::warn!("Reassembled log line exceeds maximum size, truncating it");
+                    buffer.truncated = true;
+                }
+                buffer
+                    .msg
+                    .extend_from_slice(&chunk[..chunk.len().min(room)]);
+                if is_complete {
+                    partial.take().map(|p| (p.ts, Cow::Owned(p.msg)))
+                } else {
+                    buffer.deadline = Instant::now() + self.limits.timeout;
+                    None
+                }
+            }
+        }
+    }
+
+    /// Point in time the next incomplete log line has to be flushed at
+    pub fn deadline(&self) -> Option<Instant> {
+        [self.stdout.as_ref(), self.stderr.as_ref()]
+            .into_iter()
+            .flatten()
+            .map(|partial| partial.deadline)
+            .min()
+    }
+
+    /// Timestamp of the earliest incomplete log line
+    pub fn pending_since(&self) -> Option<DateTime<Utc>> {
+        [self.stdout.as_ref(), self.stderr.as_ref()]
+            .into_iter()
+            .flatten()
+            .map(|partial| partial.ts)
+            .min()
+    }
+
+    /// Returns the incomplete log lines whose flush timeout expired, together with their stream
+    pub fn flush_expired(&mut self) -> Vec<(bool, DateTime<Utc>, Vec<u8>)> {
+        let now = Instant::now();
+        [(false, &mut self.stdout), (true, &mut self.stderr)]
+            .into_iter()
+            .filter(|(_, partial)| partial.as_ref().is_some_and(|p| p.deadline <= now))
+            .filter_map(|(is_err, partial)| partial.take().map(|p| (is_err, p.ts, p.msg)))
+            .collect()
+    }
+
+    /// Returns all incomplete log lines, together with their stream
+    pub fn flush(&mut self) -> Vec<(bool, DateTime<Utc>, Vec<u8>)> {
+        [(false, self.stdout.take()), (true, self.stderr.take())]
+            .into_iter()
+            .filter_map(|(is_err, partial)| partial.map(|p| (is_err, p.ts, p.msg)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Limits, Partials};
+    use chrono::{DateTime, Utc};
+    use std::time::Duration;
+
+    fn ts(secs: i64) -> DateTime<Utc> {
+        DateTime::from_timestamp(secs, 0).expect("valid timestamp")
+    }
+
+    fn partials(max_size: usize, timeout: Duration) -> Partials {
+        Partials::new(Limits { max_size, timeout })
+    }
+
+    #[test]
+    fn reassembles_chunks_per_stream() {
+        let mut partials = partials(1024, Duration::from_secs(30));
+        assert!(partials.push(false, ts(1), b"out ").is_none());
+        assert!(partials.push(true, ts(2), b"err ").is_none());
+        assert_eq!(partials.pending_since(), Some(ts(1)));
+
+        let (line_ts, line) = partials
+            .push(true, ts(3), b"line\n")
+            .expect("complete stderr line");
+        assert_eq!((line_ts, line.as_ref()), (ts(2), b"err line\n".as_slice()));
+        let (line_ts, line) = partials
+            .push(false, ts(4), b"line\n")
+            .expect("complete stdout line");
+        assert_eq!((line_ts, line.as_ref()), (ts(1), b"out line\n".as_slice()));
+
+        assert_eq!(partials.pending_since(), None);
+        assert!(partials.deadline().is_none());
+    }
+
+    #[test]
+    fn passes_complete_lines_through() {
+        let mut partials = partials(1024, Duration::from_secs(30));
+        let (line_ts, line) = partials
+            .push(false, ts(1), b"line\n")
+            .expect("complete line");
+        assert_eq!((line_ts, line.as_ref()), (ts(1), b"line\n".as_slice()));
+    }
+
+    #[test]
+    fn truncates_lines_exceeding_maximum_size() {
+        let mut partials = partials(8, Duration::from_secs(30));
+        assert!(partials.push(false, ts(1), b"01234").is_none());
+        assert!(partials.push(false, ts(2), b"56789").is_none());
+        let (_, line) = partials
+            .push(false, ts(3), b"abc\n")
+            .expect("complete line");
+        assert_eq!(line.as_ref(), b"01234567");
+    }
+
+    #[test]
+    fn flushes_expired_lines_only() {
+        let mut expiring = partials(1024, Duration::ZERO);
+        assert!(expiring.push(true, ts(1), b"incomplete").is_none());
+        assert_eq!(
+            expiring.flush_expired(),
+            vec![(true, ts(1), b"incomplete".to_vec())]
+        );
+        assert!(expiring.flush().is_empty());
+
+        let mut pending = partials(1024, Duration::from_secs(30));
+        assert!(pending.push(false, ts(1), b"incomplete").is_none());
+        assert!(pending.flush_expired().is_empty());
+        assert_eq!(
+            pending.flush(),
+            vec![(false, ts(1), b"incomplete".to_vec())]
+        );
+    }
+}
@@ -0,0 +1,124 @@
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use tokio_util::sync::CancellationToken;
+
+/// Registration of a running collector
+pub struct Collector {
+    pub container_id: String,
+    /// Cancelled to detach the collector from its container
+    pub detach_token: CancellationToken,
+    generation: u64,
+    /// Cancelled once the collector ended
+    ended: CancellationToken,
+    /// End of the detached collector still draining the container when this one got registered
+    predecessor: Option<CancellationToken>,
+}
+
+impl Collector {
+    /// Waits for the previous collector of the container to finish draining its log stream
+    pub async fn predecessor_ended(&self) {
+        if let Some(predecessor) = &self.predecessor {
+            predecessor.cancelled().await;
+        }
+    }
+}
+
+struct Running {
+    generation: u64,
+    detach_token: CancellationToken,
+    ended: CancellationToken,
+    /// Whether the container got removed while the collector was running
+    destroyed: bool,
+}
+
+#[derive(Default)]
+struct Collectors {
+    running: HashMap<String, Running>,
+    next_generation: u64,
+}
+
+/// Running collectors of an engine by container ID, including detached ones still draining
+#[derive(Default)]
+pub struct Registry {
+    collectors: Mutex<Collectors>,
+}
+
+impl Registry {
+    fn lock(&self) -> MutexGuard<'_, Collectors> {
+        self.collectors
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Registers a collector of the container, `None` if an attached one is already running.
+    /// A detached collector still draining gets replaced, with the new one waiting for its end.
+    pub fn register(&self, container_id: &str) -> Option<Collector> {
+        let mut collectors = self.lock();
+        let predecessor = match collectors.running.get(container_id) {
+            Some(running) if !running.detach_token.is_cancelled() => return None,
+            running => running.map(|running| running.ended.clone()),
+        };
+        let generation = collectors.next_generation;
+        collectors.next_generation += 1;
+        let detach_token = CancellationToken::new();
+        let ended = CancellationToken::new();
+        collectors.running.insert(
+            container_id.to_string(),
+            Running {
+                generation,
+                detach_token: detach_token.clone(),
+                ended: ended.clone(),
+                destroyed: false,
+            },
+        );
+        drop(collectors);
+
+        Some(Collector {
+            container_id: container_id.to_string(),
+            detach_token,
+            generation,
+            ended,
+            predecessor,
+        })
+    }
+
+    /// Removes the collector after it ended, unless it got replaced in the meantime, returning
+    /// whether its container got removed
+    pub fn unregister(&self, collector: &Collector) -> bool {
+        collector.ended.cancel();
+        let mut collectors = self.lock();
+        if collectors
+            .running
+            .get(&collector.container_id)
+            .is_some_and(|running| running.generation == collector.generation)
+        {
+            return collectors
+                .running
+                .remove(&collector.container_id)
+                .is_some_and(|running| running.destroyed);
+        }
+        false
+    }
+
+    /// Detaches the collector of the removed container, returning whether one is running
+    pub fn destroy(&self, container_id: &str) -> bool {
+        self.detach(container_id);
+        let mut collectors = self.lock();
+        collectors
+            .running
+            .get_mut(container_id)
+            .map(|running| running.destroyed = true)
+            .is_some()
+    }
+
+    /// Detaches the collector of the container, which drains its log stream before ending
+    pub fn detach(&self, container_id: &str) {
+        let collectors = self.lock();
+        if let Some(running) = collectors.running.get(container_id) {
+            if !running.detach_token.is_cancelled() {
+                tracing::debug!("Detaching from container \"{container_id}\"");
+                running.detach_token.cancel();
+            }
+        }
+    }
+}
@@ -0,0 +1,215 @@
+use crate::emitter::{self, Destination, Overflow};
+use crate::EmitterData;
+use futures_util::future::join_all;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+
+const LABEL: &str = "de.hammer065.docker-log-emitter.destination";
+const DEFAULT_ROUTE: &str = "default";
+const URL_PREFIX: &str = "EMITTER_URL_";
+
+fn route_names(s: &str) -> Vec<String> {
+    s.split(',')
+        .map(|name| name.trim().to_ascii_lowercase())
+        .filter(|name| !name.is_empty())
+        .collect()
+}
+
+/// Queues the data for all given destinations at once, handling full queues by their overflow
+/// policy so one slow destination does not hold up the others
+pub async fn send(destinations: &[Arc<Destination>], data: EmitterData) {
+    join_all(
+        destinations
+            .iter()
+            .zip(data.copies(destinations.len()))
+            .map(|(destination, data)| destination.push(data)),
+    )
+    .await;
+}
+
+/// Named emitter destinations, each one with its own queue
+pub struct Router {
+    routes: HashMap<String, Arc<Destination>>,
+    default: Vec<String>,
+}
+
+impl Router {
+    /// Starts the emitters of `EMITTER_URL` as `default` route and of all `EMITTER_URL_<NAME>`
+    /// environment variables as routes named after their lower case suffix
+    pub fn start(
+        cancellation_token: &CancellationToken,
+        tracker: &TaskTracker,
+    ) -> Result<Self, String> {
+        let mut urls: Vec<(String, String)> = std::env::vars()
+            .filter_map(|(key, url)| {
+                let name = key.strip_prefix(URL_PREFIX)?.to_ascii_lowercase();
+                Some((name, url))
+            })
+            .collect();
+        if let Ok(url) = std::env::var("EMITTER_URL") {
+            urls.push((DEFAULT_ROUTE.to_string(), url));
+        }
+        if urls.is_empty() {
+            return Err("Could not get EMITTER_URL environment variable".to_string());
+        }
+
+        let default = match std::env::var("EMITTER_DEFAULT_DESTINATION") {
+            Ok(names) => route_names(&names),
+            // A single named destination is the default one without further configuration
+            Err(_) if urls.len() == 1 => vec![urls[0].0.clone()],
+            Err(_) if urls.iter().any(|(name, _)| name == DEFAULT_ROUTE) => {
+                vec![DEFAULT_ROUTE.to_string()]
+            }
+            Err(_) => {
+                return Err(
+                    "Missing EMITTER_DEFAULT_DESTINATION to select between named destinations"
+                        .to_string(),
+                )
+            }
+        };
+        if let Some(name) = default
+            .iter()
+            .find(|name| !urls.iter().any(|(url_name, _)| url_name == *name))
+        {
+            return Err(format!("Unknown default destination \"{name}\""));
+        }
+
+        let overflow = Overflow::from_env()?;
+        let mut routes = HashMap::with_capacity(urls.len());
+        for (name, url) in urls {
+            let (tx, rx) = tokio::sync::mpsc::channel(emitter::QUEUE_SIZE);
+            let task = emitter::start(&url, cancellation_token.clone(), rx)
+                .map_err(|err| format!("{err} (destination \"{name}\")"))?;
+            tracker.spawn(task);
+            routes.insert(
+                name.clone(),
+                Arc::new(Destination::new(&name, tx, overflow)),
+            );
+        }
+
+        Ok(Self { routes, default })
+    }
+
+    /// Queues of the destinations selected by the container labels, falling back to the default route
+    pub fn route(
+        &self,
+        labels: &HashMap<String, String>,
+        container_id: &str,
+    ) -> Vec<Arc<Destination>> {
+        let names = labels
+            .get(LABEL)
+            .map(|names| route_names(names))
+            .unwrap_or_default();
+
+        let mut routes = Vec::with_capacity(names.len());
+        for name in &names {
+            match self.routes.get(name) {
+                Some(destination) => routes.push(destination.clone()),
+                None => {
+                    tracing::warn!(
+                        "Unknown destination \"{name}\" of container \"{container_id}\""
+                    );
+                }
+            }
+        }
+        if routes.is_empty() {
+            return self.default_route();
+        }
+
+        routes
+    }
+
+    /// Queues of the default destinations
+    pub fn default_route(&self) -> Vec<Arc<Destination>> {
+        self.default
+            .iter()
+            .filter_map(|name| self.routes.get(name).cloned())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::send;
+    use crate::emitter::{Destination, Overflow};
+    use crate::{Ack, EmitterData};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use tokio::sync::mpsc::Receiver;
+
+    fn destination(overflow: Overflow) -> (Arc<Destination>, Receiver<EmitterData>) {
+        let (tx, rx) = tokio::sync::mpsc::channel(1);
+        (Arc::new(Destination::new("test", tx, overflow)), rx)
+    }
+
+    fn acknowledged() -> (Arc<AtomicBool>, Ack) {
+        let flag = Arc::new(AtomicBool::new(false));
+        let on_emitted = flag.clone();
+        let ack = Ack::new(move || on_emitted.store(true, Ordering::SeqCst));
+        (flag, ack)
+    }
+
+    #[tokio::test]
+    async fn acknowledged_once_all_queued_copies_got_emitted() {
+        let (a, mut rx_a) = destination(Overflow::DropNewest);
+        let (b, mut rx_b) = destination(Overflow::DropNewest);
+        let (flag, ack) = acknowledged();
+
+        send(&[a, b], EmitterData::new(b"line\n".to_vec(), Some(ack))).await;
+
+        rx_a.recv().await.expect("queued copy").emitted();
+        assert!(!flag.load(Ordering::SeqCst));
+        let copy = rx_b.recv().await.expect("queued copy");
+        assert_eq!(copy.bytes, b"line\n");
+        copy.emitted();
+        assert!(flag.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn not_acknowledged_if_a_copy_got_dropped() {
+        let (a, mut rx_a) = destination(Overflow::DropNewest);
+        let (b, mut rx_b) = destination(Overflow::DropNewest);
+        let (flag, ack) = acknowledged();
+
+        send(&[a, b], EmitterData::new(b"line\n".to_vec(), Some(ack))).await;
+
+        drop(rx_a.recv().await.expect("queued copy"));
+        rx_b.recv().await.expect("queued copy").emitted();
+        assert!(!flag.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn acknowledged_by_single_queue() {
+        let (destination, mut rx) = destination(Overflow::Block);
+        let (flag, ack) = acknowledged();
+
+        send(
+            &[destination],
+            EmitterData::new(b"line\n".to_vec(), Some(ack)),
+        )
+        .await;
+
+        rx.recv().await.expect("queued copy").emitted();
+        assert!(flag.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn full_queue_does_not_hold_up_others() {
+        let (slow, mut rx_slow) = destination(Overflow::DropNewest);
+        let (fast, mut rx_fast) = destination(Overflow::DropNewest);
+        let destinations = [slow, fast];
+
+        send(&destinations, EmitterData::new(b"first\n".to_vec(), None)).await;
+        assert_eq!(rx_fast.recv().await.expect("first line").bytes, b"first\n");
+        send(&destinations, EmitterData::new(b"second\n".to_vec(), None)).await;
+        assert_eq!(
+            rx_fast.recv().await.expect("second line").bytes,
+            b"second\n"
+        );
+
+        assert_eq!(rx_slow.recv().await.expect("first line").bytes, b"first\n");
+        assert!(rx_slow.try_recv().is_err());
+    }
+}
@@ -0,0 +1,453 @@
+use crate::metadata::Metadata;
+#[cfg(feature = "tls")]
+use crate::tls;
+use bollard::models::EventMessage;
+use bollard::{Docker, API_DEFAULT_VERSION};
+use http_body_util::{BodyExt, Empty};
+use hyper::body::Bytes;
+use hyper_util::client::legacy::Client;
+use hyperlocal::{UnixClientExt, UnixConnector, Uri};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
+/// Request timeout in seconds
+const TIMEOUT: u64 = 120;
+const LIBPOD_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// API sockets of Docker, rootful Podman and rootless Podman, tried in this order
+fn socket_candidates() -> Vec<PathBuf> {
+    let mut candidates = vec![
+        PathBuf::from("/var/run/docker.sock"),
+        PathBuf::from("/run/podman/podman.sock"),
+    ];
+    if let Some(runtime_dir) = std::env::var_os("XDG_RUNTIME_DIR") {
+        candidates.push(PathBuf::from(runtime_dir).join("podman/podman.sock"));
+    }
+    candidates
+}
+
+/// First existing default API socket, falling back to the Docker one
+fn default_socket() -> PathBuf {
+    let mut candidates = socket_candidates();
+    let index = candidates
+        .iter()
+        .position(|path| path.exists())
+        .unwrap_or(0);
+    candidates.swap_remove(index)
+}
+
+/// Error message including all of its sources, e.g. the TLS error behind a connection failure
+fn describe(err: &dyn std::error::Error) -> String {
+    let mut msg = err.to_string();
+    let mut source = err.source();
+    while let Some(err) = source {
+        // Some errors already include their source in their own message
+        let text = err.to_string();
+        if !msg.ends_with(&text) {
+            let _ = write!(msg, ": {text}");
+        }
+        source = err.source();
+    }
+    msg
+}
+
+/// Client of the native Podman API, whose compatible API used by bollard lacks pods
+struct Libpod {
+    client: Client<UnixConnector, Empty<Bytes>>,
+    socket: PathBuf,
+    /// Pod names by container ID, as containers never change their pod
+    pods: Mutex<HashMap<String, Option<String>>>,
+}
+
+impl Libpod {
+    fn new(socket: PathBuf) -> Self {
+        Self {
+            client: Client::unix(),
+            socket,
+            pods: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, Option<String>>> {
+        self.pods.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Sends a GET request, returning the response body on success
+    async fn get(&self, path: &str) -> Result<Bytes, String> {
+        let response = self
+            .client
+            .get(Uri::new(&self.socket, path).into())
+            .await
+            .map_err(|err| describe(&err))?;
+        let status = response.status();
+        let body = response
+            .into_body()
+            .collect()
+            .await
+            .map_err(|err| describe(&err))?
+            .to_bytes();
+        if !status.is_success() {
+            return Err(format!("status {status}"));
+        }
+        Ok(body)
+    }
+
+    /// Fetches the name of the pod the container belongs to, `None` if it has none
+    async fn fetch_pod_name(&self, container_id: &str) -> Result<Option<String>, String> {
+        // Filtered by `{"id":["<id>"]}`
+        let path = format!(
+            "/libpod/containers/json?all=true&filters=%7B%22id%22%3A%5B%22{container_id}%22%5D%7D"
+        );
+        let body = tokio::time::timeout(LIBPOD_TIMEOUT, self.get(&path))
+            .await
+            .map_err(|_| "timeout".to_string())??;
+
+        let containers: serde_json::Value =
+            serde_json::from_slice(&body).map_err(|err| err.to_string())?;
+        Ok(containers
+            .get(0)
+            .and_then(|container| container.get("PodName"))
+            .and_then(serde_json::Value::as_str)
+            .filter(|name| !name.is_empty())
+            .map(String::from))
+    }
+
+    /// Name of the pod the container belongs to, fetched once per container
+    async fn pod_name(&self, container_id: &str) -> Option<String> {
+        if let Some(pod) = self.lock().get(container_id) {
+            return pod.clone();
+        }
+        match self.fetch_pod_name(container_id).await {
+            Ok(pod) => {
+                self.lock().insert(container_id.to_string(), pod.clone());
+                pod
+            }
+            Err(err) => {
+                tracing::debug!("Could not fetch pod of container \"{container_id}\": {err}");
+                None
+            }
+        }
+    }
+}
+
+/// CA bundle and optional client certificate of a TLS secured endpoint
+#[cfg(feature = "tls")]
+struct TlsFiles {
+    ca: PathBuf,
+    cert: Option<PathBuf>,
+    key: Option<PathBuf>,
+}
+
+#[cfg(feature = "tls")]
+impl TlsFiles {
+    /// Files named `ca.pem`, `cert.pem` and `key.pem` in `DOCKER_CERT_PATH_<NAME>`. Otherwise
+    /// the files given by `DOCKER_TLS_CA_CERT`, `DOCKER_TLS_CERT` and `DOCKER_TLS_KEY`, which
+    /// default to the ones in `DOCKER_CERT_PATH` or `~/.docker`.
+    fn from_env(name: &str) -> Self {
+        let name: String = name
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() {
+                    c.to_ascii_uppercase()
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        let endpoint_dir = std::env::var_os(format!("DOCKER_CERT_PATH_{name}")).map(PathBuf::from);
+        let dir = endpoint_dir.clone().unwrap_or_else(|| {
+            std::env::var_os("DOCKER_CERT_PATH").map_or_else(
+                || {
+                    std::env::var_os("HOME")
+                        .map(|home| PathBuf::from(home).join(".docker"))
+                        .unwrap_or_default()
+                },
+                PathBuf::from,
+            )
+        });
+        let file = |var: &str, default: &str| match std::env::var_os(var) {
+            Some(path) if endpoint_dir.is_none() => PathBuf::from(path),
+            _ => dir.join(default),
+        };
+
+        let cert = file("DOCKER_TLS_CERT", "cert.pem");
+        let key = file("DOCKER_TLS_KEY", "key.pem");
+        // Without client certificate, only the server gets verified
+        let client_auth = cert.exists() || key.exists();
+        Self {
+            ca: file("DOCKER_TLS_CA_CERT", "ca.pem"),
+            cert: client_auth.then_some(cert),
+            key: client_auth.then_some(key),
+        }
+    }
+}
+
+/// Docker-compatible API endpoint to collect container logs from
+pub struct Endpoint {
+    pub name: String,
+    /// Hostname of the syslog messages emitted for this endpoint
+    pub hostname: String,
+    /// Prefix of the checkpoints, empty for the local engine to keep the checkpoints of
+    /// single host setups
+    scope: String,
+    /// API URL, detected from the default sockets if `None`
+    url: Option<String>,
+    #[cfg(feature = "tls")]
+    tls: Option<TlsFiles>,
+}
+
+impl Endpoint {
+    fn new(name: &str, hostname: &str, scope: &str, url: Option<String>) -> Result<Self, String> {
+        let Some(url) = url else {
+            return Ok(Self {
+                name: name.to_string(),
+                hostname: hostname.to_string(),
+                scope: scope.to_string(),
+                url: None,
+                #[cfg(feature = "tls")]
+                tls: None,
+            });
+        };
+        if !["unix://", "tcp://", "http://", "https://"]
+            .iter()
+            .any(|scheme| url.starts_with(scheme))
+        {
+            return Err(format!(
+                "Unsupported URL \"{url}\" of Docker endpoint \"{name}\""
+            ));
+        }
+
+        // Same semantics as the Docker CLI, which enables TLS for any non-empty value
+        let verify = std::env::var("DOCKER_TLS_VERIFY").is_ok_and(|v| !v.is_empty());
+        let use_tls = url.starts_with("https://") || (url.starts_with("tcp://") && verify);
+        #[cfg(not(feature = "tls"))]
+        if use_tls {
+            return Err(format!(
+                "Docker endpoint \"{name}\" requires the tls feature for TLS connections"
+            ));
+        }
+
+        Ok(Self {
+            name: name.to_string(),
+            hostname: hostname.to_string(),
+            scope: scope.to_string(),
+            #[cfg(feature = "tls")]
+            tls: use_tls.then(|| TlsFiles::from_env(name)),
+            url: Some(url),
+        })
+    }
+
+    /// Endpoints configured by `DOCKER_HOSTS` as comma separated `name=url` pairs, or the
+    /// local engine or `DOCKER_HOST` otherwise. The names are used as hostnames of the
+    /// emitted messages.
+    pub fn from_env(hostname: &str) -> Result<Vec<Self>, String> {
+        let Ok(value) = std::env::var("DOCKER_HOSTS") else {
+            return Ok(vec![Self::new(
+                "local",
+                hostname,
+                "",
+                std::env::var("DOCKER_HOST").ok(),
+            )?]);
+        };
+
+        let mut endpoints: Vec<Self> = Vec::new();
+        for endpoint in value.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let Some((name, url)) = endpoint.split_once('=') else {
+                return Err(format!("Invalid DOCKER_HOSTS endpoint \"{endpoint}\""));
+            };
+            let (name, url) = (name.trim(), url.trim());
+            if name.is_empty() || name.contains(|c: char| c == '/' || !c.is_ascii_graphic()) {
+                return Err(format!("Invalid DOCKER_HOSTS endpoint name \"{name}\""));
+            }
+            if endpoints.iter().any(|e| e.name == name) {
+                return Err(format!("Duplicate DOCKER_HOSTS endpoint name \"{name}\""));
+            }
+            endpoints.push(Self::new(name, name, name, Some(url.to_string()))?);
+        }
+        if endpoints.is_empty() {
+            return Err("No endpoints in DOCKER_HOSTS".to_string());
+        }
+
+        Ok(endpoints)
+    }
+
+    /// API client and socket path of local engines
+    fn client(&self) -> Result<(Docker, Option<PathBuf>), String> {
+        let Some(url) = self.url.as_deref() else {
+            let socket = default_socket();
+            let docker =
+                Docker::connect_with_unix(&socket.to_string_lossy(), TIMEOUT, API_DEFAULT_VERSION)
+                    .map_err(|err| describe(&err))?;
+            return Ok((docker, Some(socket)));
+        };
+
+        #[cfg(feature = "tls")]
+        if let Some(tls) = self.tls.as_ref() {
+            // The client certificate would be left out silently if it was unreadable
+            tls::connector(Some(&tls.ca), tls.cert.as_deref(), tls.key.as_deref())?;
+            let (cert, key) = (
+                tls.cert.clone().unwrap_or_default(),
+                tls.key.clone().unwrap_or_default(),
+            );
+            let docker =
+                Docker::connect_with_ssl(url, &key, &cert, &tls.ca, TIMEOUT, API_DEFAULT_VERSION)
+                    .map_err(|err| describe(&err))?;
+            return Ok((docker, None));
+        }
+
+        match url.strip_prefix("unix://") {
+            Some(path) => Ok((
+                Docker::connect_with_unix(path, TIMEOUT, API_DEFAULT_VERSION)
+                    .map_err(|err| describe(&err))?,
+                Some(PathBuf::from(path)),
+            )),
+            None => Ok((
+                Docker::connect_with_http(url, TIMEOUT, API_DEFAULT_VERSION)
+                    .map_err(|err| describe(&err))?,
+                None,
+            )),
+        }
+    }
+
+    /// Prefix of the checkpoints of this endpoint
+    pub fn checkpoint_scope(&self) -> &str {
+        &self.scope
+    }
+}
+
+/// Connection to a Docker-compatible runtime engine
+pub struct Runtime {
+    pub docker: Docker,
+    pub endpoint: Arc<Endpoint>,
+    pub metadata: Metadata,
+    /// Native API of local Podman engines
+    libpod: Option<Libpod>,
+}
+
+impl Runtime {
+    /// Connects to the endpoint, by default to the first found Docker or Podman API socket
+    pub async fn connect(endpoint: &Arc<Endpoint>) -> Result<Self, String> {
+        let (docker, socket) = endpoint.client()?;
+        // Podman and older Docker engines support lower API versions only
+        let docker = docker
+            .negotiate_version()
+            .await
+            .map_err(|err| describe(&err))?;
+
+        let is_podman = docker
+            .version()
+            .await
+            .map_err(|err| describe(&err))?
+            .components
+            .is_some_and(|components| {
+                components
+                    .iter()
+                    .any(|component| component.name.starts_with("Podman"))
+            });
+
+        Ok(Self {
+            metadata: Metadata::new(docker.clone()),
+            docker,
+            endpoint: endpoint.clone(),
+            libpod: socket.filter(|_| is_podman).map(Libpod::new),
+        })
+    }
+
+    /// Name of the Podman pod the container belongs to
+    pub async fn pod_name(&self, container_id: &str) -> Option<String> {
+        self.libpod.as_ref()?.pod_name(container_id).await
+    }
+
+    /// Updates the cached container info and pods on container events
+    pub async fn handle(&self, event: &EventMessage) {
+        self.metadata.handle(event).await;
+        if event.action.as_deref() == Some("destroy") {
+            if let (Some(libpod), Some(container_id)) = (
+                self.libpod.as_ref(),
+                event.actor.as_ref().and_then(|actor| actor.id.as_deref()),
+            ) {
+                libpod.lock().remove(container_id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Libpod;
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::UnixListener;
+
+    /// Stand-in for the Podman API answering every request with the given response, counting
+    /// the requests
+    fn serve(name: &str, response: &'static str) -> (PathBuf, Arc<AtomicUsize>) {
+        let socket = std::env::temp_dir().join(format!(
+            "docker-log-emitter-{}-{name}.sock",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&socket);
+        let listener = UnixListener::bind(&socket).expect("bind test socket");
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut request = Vec::new();
+                let mut buf = [0; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    match stream.read(&mut buf).await {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => request.extend_from_slice(&buf[..n]),
+                    }
+                }
+                assert!(request.starts_with(b"GET /libpod/containers/json?"));
+                counter.fetch_add(1, Ordering::SeqCst);
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+        (socket, requests)
+    }
+
+    #[tokio::test]
+    async fn pod_name_of_chunked_response() {
+        let (socket, _) = serve(
+            "chunked",
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nTransfer-Encoding: chunked\r\n\
+             Connection: close\r\n\r\n8\r\n[{\"PodNa\r\nB\r\nme\":\"web\"}]\r\n0\r\n\r\n",
+        );
+        let libpod = Libpod::new(socket);
+        assert_eq!(libpod.pod_name("abc").await.as_deref(), Some("web"));
+    }
+
+    #[tokio::test]
+    async fn pod_name_cached_per_container() {
+        let (socket, requests) = serve(
+            "cached",
+            "HTTP/1.1 200 OK\r\nContent-Length: 16\r\nConnection: close\r\n\r\n[{\"PodName\":\"\"}]",
+        );
+        let libpod = Libpod::new(socket);
+        assert_eq!(libpod.pod_name("abc").await, None);
+        assert_eq!(libpod.pod_name("abc").await, None);
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+        assert_eq!(libpod.pod_name("def").await, None);
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn pod_name_not_cached_on_error_status() {
+        let (socket, requests) = serve(
+            "error",
+            "HTTP/1.1 500 Internal Server Error\r\nContent-Length: 19\r\nConnection: close\r\n\r\n\
+             [{\"PodName\":\"web\"}]",
+        );
+        let libpod = Libpod::new(socket);
+        assert_eq!(libpod.pod_name("abc").await, None);
+        assert_eq!(libpod.pod_name("abc").await, None);
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,99 @@
+use crate::syslog::Severity;
+use regex::bytes::{Regex, RegexSet};
+use std::collections::HashMap;
+
+const LABEL: &str = "de.hammer065.docker-log-emitter.severity_rules";
+
+/// Log level keywords of common frameworks like log4j, Python logging, nginx and Postgres,
+/// ordered from the most to the least severe
+const DEFAULT_RULES: [(Severity, &str); 8] = [
+    (Severity::Emergency, "emerg|emergency"),
+    (Severity::Alert, "alert"),
+    (Severity::Critical, "crit|critical|fatal|panic"),
+    (Severity::Error, "err|error|severe"),
+    (Severity::Warning, "warn|warning"),
+    (Severity::Notice, "notice"),
+    (Severity::Informational, "info|information"),
+    (Severity::Debug, "debug[1-5]?|trace|fine|finer|finest"),
+];
+
+/// Matches the keywords as one of the first tokens of a log line, either in upper case,
+/// enclosed in brackets or followed by a colon, e.g. `WARN`, `[error]` or `Error:`
+fn keyword_rule(keywords: &str) -> String {
+    let upper = keywords.to_uppercase();
+    format!(
+        r"^(?:\S+\s+){{0,6}}?(?:[\[(<]?(?:{upper})(?:[\])>:]|\s|$)|[\[(<](?i:{keywords})[\])>]|(?i:{keywords}):)"
+    )
+}
+
+/// Classifies log lines by the rule matching earliest in the line
+pub struct Classifier {
+    set: RegexSet,
+    rules: Vec<(Severity, Regex)>,
+}
+
+impl Classifier {
+    /// Builds the rules selected by the container labels, either the defaults, custom rules
+    /// or none at all. Custom rules replace the default rule of the same severity, while
+    /// the defaults only apply if selected explicitly.
+    pub fn from_labels(labels: &HashMap<String, String>) -> Result<Option<Self>, String> {
+        let mut rules: Vec<(Severity, String)> = match labels
+            .get(LABEL)
+            .cloned()
+            .or_else(|| std::env::var("SEVERITY_RULES").ok())
+            .as_deref()
+            .map(str::trim)
+        {
+            Some("default") => DEFAULT_RULES
+                .iter()
+                .map(|(severity, keywords)| (*severity, keyword_rule(keywords)))
+                .collect(),
+            None | Some("custom") => Vec::new(),
+            Some("none") => return Ok(None),
+            Some(rules) => return Err(format!("Unknown severity rules \"{rules}\"")),
+        };
+
+        let prefix = format!("{LABEL}.");
+        for (key, pattern) in labels {
+            let Some(name) = key.strip_prefix(&prefix) else {
+                continue;
+            };
+            let severity = Severity::from_name(name)
+                .ok_or_else(|| format!("Unknown severity \"{name}\" in label {key}"))?;
+            rules.retain(|(s, _)| *s != severity);
+            rules.push((severity, pattern.clone()));
+        }
+        if rules.is_empty() {
+            return Ok(None);
+        }
+        rules.sort_by_key(|(severity, _)| severity.numerical_code());
+
+        let set = RegexSet::new(rules.iter().map(|(_, pattern)| pattern))
+            .map_err(|err| format!("Invalid severity rule: {err}"))?;
+        let rules = rules
+            .into_iter()
+            .map(|(severity, pattern)| {
+                Regex::new(&pattern)
+                    .map(|regex| (severity, regex))
+                    .map_err(|err| format!("Invalid severity rule: {err}"))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Some(Self { set, rules }))
+    }
+
+    /// Returns the severity of the rule whose match ends first in the first line of the
+    /// message, the most severe one on ties. Keywords like in `INFO Error: …` thereby
+    /// classify by the log level, not by words of the message.
+    pub fn classify(&self, msg: &[u8]) -> Option<Severity> {
+        let line = msg.split(|b| *b == b'\n').next().unwrap_or(msg);
+        self.set
+            .matches(line)
+            .iter()
+            .filter_map(|i| {
+                let (severity, regex) = &self.rules[i];
+                regex.find(line).map(|m| (m.end(), i, *severity))
+            })
+            .min_by_key(|(end, i, _)| (*end, *i))
+            .map(|(_, _, severity)| severity)
+    }
+}
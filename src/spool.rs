@@ -0,0 +1,507 @@
+use std::collections::VecDeque;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufReader};
+
+/// Size of a record header: data length and CRC32 checksum, both little endian `u32`
+const HEADER_SIZE: u64 = 8;
+const SEGMENT_EXTENSION: &str = "seg";
+const CURSOR_FILE: &str = "cursor";
+/// Acknowledged records between cursor persistence
+const CURSOR_INTERVAL: u32 = 128;
+
+#[derive(Clone, Copy)]
+pub enum Overflow {
+    DropOldest,
+    DropNewest,
+}
+
+struct Segment {
+    seq: u64,
+    size: u64,
+}
+
+struct Record {
+    data: Vec<u8>,
+    size: u64,
+}
+
+/// Segmented, append-only on-disk queue of emitter data
+pub struct Spool {
+    dir: PathBuf,
+    max_size: u64,
+    segment_size: u64,
+    overflow: Overflow,
+    segments: VecDeque<Segment>,
+    /// Sequence number of the next segment, never reused
+    next_seq: u64,
+    size: u64,
+    writer: Option<tokio::fs::File>,
+    reader: Option<BufReader<tokio::fs::File>>,
+    read_offset: u64,
+    peeked: Option<Record>,
+    unsaved_acks: u32,
+}
+
+fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let (number, factor) = match s.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&s[..i], 1 << 10),
+        Some((i, 'm' | 'M')) => (&s[..i], 1 << 20),
+        Some((i, 'g' | 'G')) => (&s[..i], 1 << 30),
+        _ => (s, 1),
+    };
+    number
+        .trim()
+        .parse::<u64>()
+        .map(|n| n * factor)
+        .map_err(|err| format!("Invalid size \"{s}\": {err}"))
+}
+
+fn segment_path(dir: &Path, seq: u64) -> PathBuf {
+    dir.join(format!("{seq:020}.{SEGMENT_EXTENSION}"))
+}
+
+impl Spool {
+    /// Opens the spool configured by the `EMITTER_SPOOL_*` environment variables, if any,
+    /// using a subdirectory named after the given destination
+    pub fn from_env(name: &str) -> Result<Option<Self>, String> {
+        let Some(dir) = std::env::var_os("EMITTER_SPOOL_DIR").map(PathBuf::from) else {
+            return Ok(None);
+        };
+        let max_size =
+            std::env::var("EMITTER_SPOOL_MAX_SIZE").map_or(Ok(256 << 20), |v| parse_size(&v))?;
+        let overflow = match std::env::var("EMITTER_SPOOL_OVERFLOW").as_deref() {
+            Err(_) | Ok("drop-oldest") => Overflow::DropOldest,
+            Ok("drop-newest") => Overflow::DropNewest,
+            Ok(overflow) => return Err(format!("Unknown spool overflow policy \"{overflow}\"")),
+        };
+
+        let name: String = name
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect();
+        Self::open(dir.join(name), max_size, overflow).map(Some)
+    }
+
+    pub fn open(dir: PathBuf, max_size: u64, overflow: Overflow) -> Result<Self, String> {
+        std::fs::create_dir_all(&dir)
+            .map_err(|err| format!("Could not create spool \"{}\": {err}", dir.display()))?;
+
+        let entries = std::fs::read_dir(&dir)
+            .map_err(|err| format!("Could not read spool \"{}\": {err}", dir.display()))?;
+        let mut segments = Vec::new();
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.extension().and_then(|e| e.to_str()) != Some(SEGMENT_EXTENSION) {
+                continue;
+            }
+            let Some(seq) = path
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .and_then(|s| s.parse().ok())
+            else {
+                continue;
+            };
+            let size = entry.metadata().map_or(0, |m| m.len());
+            segments.push(Segment { seq, size });
+        }
+        segments.sort_unstable_by_key(|s| s.seq);
+        let mut next_seq = segments.last().map_or(0, |s| s.seq + 1);
+
+        // Restore the read position, falling back to the start of the oldest segment
+        let mut read_offset = 0;
+        if let Some((seq, offset)) = std::fs::read_to_string(dir.join(CURSOR_FILE))
+            .ok()
+            .and_then(|cursor| {
+                let (seq, offset) = cursor.trim().split_once(' ')?;
+                Some((seq.parse::<u64>().ok()?, offset.parse::<u64>().ok()?))
+            })
+        {
+            next_seq = next_seq.max(seq + 1);
+            for segment in segments.iter().take_while(|s| s.seq < seq) {
+                if let Err(err) = std::fs::remove_file(segment_path(&dir, segment.seq)) {
+                    tracing::warn!("Could not remove spool segment: {err}");
+                }
+            }
+            segments.retain(|s| s.seq >= seq);
+            if segments.first().is_some_and(|s| s.seq == seq) {
+                read_offset = offset;
+            }
+        }
+
+        let size = segments.iter().map(|s| s.size).sum();
+        let mut spool = Self {
+            dir,
+            max_size,
+            segment_size: (max_size / 8).clamp(1, 16 << 20),
+            overflow,
+            segments: segments.into(),
+            next_seq,
+            size,
+            writer: None,
+            reader: None,
+            read_offset,
+            peeked: None,
+            unsaved_acks: 0,
+        };
+        // The last segment may end with a record torn by a crash, so new records go to a
+        // fresh segment instead of following it
+        spool.roll();
+        if !spool.is_empty() {
+            tracing::info!(
+                "Spool \"{}\" contains {} bytes to replay",
+                spool.dir.display(),
+                spool.pending()
+            );
+        }
+
+        Ok(spool)
+    }
+
+    const fn pending(&self) -> u64 {
+        self.size.saturating_sub(self.read_offset)
+    }
+
+    pub const fn is_empty(&self) -> bool {
+        self.pending() == 0
+    }
+
+    fn remove_oldest(&mut self) {
+        let Some(segment) = self.segments.pop_front() else {
+            return;
+        };
+        self.size -= segment.size;
+        self.read_offset = 0;
+        self.reader = None;
+        self.peeked = None;
+        if self.segments.is_empty() {
+            self.writer = None;
+        }
+        match std::fs::remove_file(segment_path(&self.dir, segment.seq)) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                tracing::warn!("Could not remove spool segment: {err}");
+            }
+            _ => {}
+        }
+        self.save_cursor();
+    }
+
+    fn save_cursor(&mut self) {
+        self.unsaved_acks = 0;
+        let cursor = self.segments.front().map_or_else(String::new, |segment| {
+            format!("{} {}\n", segment.seq, self.read_offset)
+        });
+        if let Err(err) = std::fs::write(self.dir.join(CURSOR_FILE), cursor) {
+            tracing::warn!("Could not save spool cursor: {err}");
+        }
+    }
+
+    /// Makes room for the given amount of bytes, returns `false` if the data should be dropped
+    fn make_room(&mut self, size: u64) -> bool {
+        if size > self.max_size {
+            return false;
+        }
+        match self.overflow {
+            Overflow::DropNewest => self.size + size <= self.max_size,
+            Overflow::DropOldest => {
+                let mut dropped = 0;
+                while self.size + size > self.max_size && !self.segments.is_empty() {
+                    dropped += self.segments[0].size.saturating_sub(self.read_offset);
+                    self.remove_oldest();
+                }
+                if dropped > 0 {
+                    tracing::warn!("Spool is full, dropped {dropped} bytes of oldest log lines");
+                }
+                true
+            }
+        }
+    }
+
+    /// Continues writing to a new segment
+    fn roll(&mut self) {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.segments.push_back(Segment { seq, size: 0 });
+        self.writer = None;
+    }
+
+    async fn writer(&mut self) -> std::io::Result<&mut tokio::fs::File> {
+        if self
+            .segments
+            .back()
+            .is_none_or(|s| s.size >= self.segment_size)
+        {
+            self.roll();
+        }
+        if self.writer.is_none() {
+            let seq = self.segments.back().expect("Created prior").seq;
+            let file = tokio::fs::OpenOptions::new()
+                .append(true)
+                .create(true)
+                .open(segment_path(&self.dir, seq))
+                .await?;
+            self.writer = Some(file);
+        }
+
+        Ok(self.writer.as_mut().expect("Opened prior"))
+    }
+
+    /// Appends the data to the end of the spool, returns `false` if it got dropped instead
+    pub async fn push(&mut self, data: &[u8]) -> bool {
+        let Ok(len) = u32::try_from(data.len()) else {
+            tracing::warn!("Log line too large for spool, dropping it");
+            return false;
+        };
+        let size = HEADER_SIZE + u64::from(len);
+        if !self.make_room(size) {
+            tracing::warn!("Spool is full, dropping log line");
+            return false;
+        }
+
+        let mut record = Vec::with_capacity(data.len() + 8);
+        record.extend_from_slice(&len.to_le_bytes());
+        record.extend_from_slice(&crc32fast::hash(data).to_le_bytes());
+        record.extend_from_slice(data);
+
+        let result = match self.writer().await {
+            Ok(writer) => match writer.write_all(&record).await {
+                Ok(()) => writer.flush().await,
+                Err(err) => Err(err),
+            },
+            Err(err) => Err(err),
+        };
+        match result {
+            Ok(()) => {
+                self.segments.back_mut().expect("Created prior").size += size;
+                self.size += size;
+                true
+            }
+            Err(err) => {
+                tracing::warn!("Could not write to spool, dropping log line: {err}");
+                // Start a fresh segment instead of appending to a possibly torn record
+                self.roll();
+                false
+            }
+        }
+    }
+
+    async fn read_record(&mut self) -> std::io::Result<Record> {
+        let segment = self.segments.front().expect("Checked prior");
+        let (seq, remaining) = (segment.seq, segment.size.saturating_sub(self.read_offset));
+        if self.reader.is_none() {
+            let mut file = tokio::fs::File::open(segment_path(&self.dir, seq)).await?;
+            file.seek(SeekFrom::Start(self.read_offset)).await?;
+            self.reader = Some(BufReader::new(file));
+        }
+        let reader = self.reader.as_mut().expect("Opened prior");
+
+        let mut header = [0u8; 8];
+        reader.read_exact(&mut header).await?;
+        let (len, checksum) = header.split_at(4);
+        let len = u32::from_le_bytes(len.try_into().expect("Split prior"));
+        let checksum = u32::from_le_bytes(checksum.try_into().expect("Split prior"));
+        if HEADER_SIZE + u64::from(len) > remaining {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "record exceeds segment",
+            ));
+        }
+
+        let mut data = vec![0u8; len as usize];
+        reader.read_exact(&mut data).await?;
+        if crc32fast::hash(&data) != checksum {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "checksum mismatch",
+            ));
+        }
+
+        Ok(Record {
+            data,
+            size: HEADER_SIZE + u64::from(len),
+        })
+    }
+
+    /// Returns the oldest data of the spool without removing it
+    pub async fn peek(&mut self) -> Option<&[u8]> {
+        while self.peeked.is_none() {
+            let segment_size = self.segments.front()?.size;
+            if self.read_offset >= segment_size {
+                if self.segments.len() == 1 {
+                    return None;
+                }
+                self.remove_oldest();
+                continue;
+            }
+
+            match self.read_record().await {
+                Ok(record) => self.peeked = Some(record),
+                Err(err) => {
+                    tracing::warn!(
+                        "Corrupted spool segment, skipping {} bytes: {err}",
+                        segment_size - self.read_offset
+                    );
+                    if self.segments.len() == 1 {
+                        self.roll();
+                    }
+                    self.remove_oldest();
+                }
+            }
+        }
+
+        self.peeked.as_ref().map(|record| record.data.as_slice())
+    }
+
+    /// Removes the oldest data of the spool after it got emitted
+    pub fn pop(&mut self) {
+        let Some(record) = self.peeked.take() else {
+            return;
+        };
+        self.read_offset += record.size;
+
+        let segment_size = self.segments.front().map_or(0, |s| s.size);
+        if self.read_offset >= segment_size && self.segments.len() > 1 {
+            self.remove_oldest();
+        } else if self.is_empty() {
+            // Fully replayed, start over with a fresh segment
+            self.remove_oldest();
+        } else {
+            self.unsaved_acks += 1;
+            if self.unsaved_acks >= CURSOR_INTERVAL {
+                self.save_cursor();
+            }
+        }
+    }
+
+    /// Persists the read position for the next process start
+    pub fn close(&mut self) {
+        self.save_cursor();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{segment_path, Overflow, Spool};
+    use std::io::Write;
+    use std::path::PathBuf;
+
+    fn spool_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "docker-log-emitter-spool-{}-{name}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    async fn peek(spool: &mut Spool) -> Option<Vec<u8>> {
+        spool.peek().await.map(<[u8]>::to_vec)
+    }
+
+    #[tokio::test]
+    async fn resumes_from_cursor_after_reopening() {
+        let dir = spool_dir("cursor");
+        let mut spool = Spool::open(dir.clone(), 1 << 20, Overflow::DropOldest).expect("open");
+        for data in [b"first", b"other", b"third"] {
+            assert!(spool.push(data).await);
+        }
+        assert_eq!(peek(&mut spool).await.as_deref(), Some(b"first".as_slice()));
+        spool.pop();
+        spool.close();
+        drop(spool);
+
+        let mut spool = Spool::open(dir.clone(), 1 << 20, Overflow::DropOldest).expect("reopen");
+        assert!(spool.push(b"fourth").await);
+        for data in [b"other".as_slice(), b"third", b"fourth"] {
+            assert_eq!(peek(&mut spool).await.as_deref(), Some(data));
+            spool.pop();
+        }
+        assert!(spool.is_empty());
+        assert_eq!(peek(&mut spool).await, None);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn skips_torn_record_at_end_of_segment() {
+        let dir = spool_dir("torn");
+        let mut spool = Spool::open(dir.clone(), 1 << 20, Overflow::DropOldest).expect("open");
+        assert!(spool.push(b"complete").await);
+        let seq = spool.segments.back().expect("segment").seq;
+        drop(spool);
+        // Header of a record whose data never got written
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(segment_path(&dir, seq))
+            .and_then(|mut file| file.write_all(&[100, 0, 0, 0, 1, 2, 3, 4, b't']))
+            .expect("tear segment");
+
+        let mut spool = Spool::open(dir.clone(), 1 << 20, Overflow::DropOldest).expect("reopen");
+        assert!(spool.push(b"new").await);
+        assert_eq!(
+            peek(&mut spool).await.as_deref(),
+            Some(b"complete".as_slice())
+        );
+        spool.pop();
+        assert_eq!(peek(&mut spool).await.as_deref(), Some(b"new".as_slice()));
+        spool.pop();
+        assert!(spool.is_empty());
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn skips_segment_with_checksum_mismatch() {
+        let dir = spool_dir("corrupt");
+        let mut spool = Spool::open(dir.clone(), 1 << 20, Overflow::DropOldest).expect("open");
+        assert!(spool.push(b"corrupted").await);
+        let seq = spool.segments.back().expect("segment").seq;
+        drop(spool);
+        let path = segment_path(&dir, seq);
+        let mut content = std::fs::read(&path).expect("read segment");
+        *content.last_mut().expect("record data") ^= 0xff;
+        std::fs::write(&path, content).expect("corrupt segment");
+
+        let mut spool = Spool::open(dir.clone(), 1 << 20, Overflow::DropOldest).expect("reopen");
+        assert!(spool.push(b"intact").await);
+        assert_eq!(
+            peek(&mut spool).await.as_deref(),
+            Some(b"intact".as_slice())
+        );
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn drops_newest_once_full() {
+        let dir = spool_dir("drop-newest");
+        // Room for two records of 8 header and 10 data bytes
+        let mut spool = Spool::open(dir.clone(), 40, Overflow::DropNewest).expect("open");
+        assert!(spool.push(b"0123456789").await);
+        assert!(spool.push(b"abcdefghij").await);
+        assert!(!spool.push(b"ABCDEFGHIJ").await);
+        assert_eq!(
+            peek(&mut spool).await.as_deref(),
+            Some(b"0123456789".as_slice())
+        );
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn drops_oldest_once_full() {
+        let dir = spool_dir("drop-oldest");
+        // Room for two records, each one in its own segment
+        let mut spool = Spool::open(dir.clone(), 40, Overflow::DropOldest).expect("open");
+        assert!(spool.push(b"0123456789").await);
+        assert!(spool.push(b"abcdefghij").await);
+        assert!(spool.push(b"ABCDEFGHIJ").await);
+        assert_eq!(
+            peek(&mut spool).await.as_deref(),
+            Some(b"abcdefghij".as_slice())
+        );
+        spool.pop();
+        assert_eq!(
+            peek(&mut spool).await.as_deref(),
+            Some(b"ABCDEFGHIJ".as_slice())
+        );
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
@@ -1,6 +1,6 @@
 use chrono::{DateTime, TimeZone};
 
-#[allow(dead_code)]
+#[derive(Clone, Copy)]
 pub enum Facility {
     Kernel,
     UserLevel,
@@ -12,10 +12,12 @@ pub enum Facility {
     NetworkNews,
     Uucp,
     ClockDaemon,
+    SecurityAuthorization,
     FtpDaemon,
     Ntp,
     LogAudit,
     LogAlert,
+    SchedulingDaemon,
     Local0,
     Local1,
     Local2,
@@ -27,7 +29,39 @@ pub enum Facility {
 }
 
 impl Facility {
-    const fn numerical_code(&self) -> u16 {
+    /// Parses facility keywords like `daemon` or `local0`, or numerical codes
+    pub fn from_name(name: &str) -> Option<Self> {
+        let name = name.trim().to_ascii_lowercase();
+        match name.as_str() {
+            "kern" | "kernel" | "0" => Some(Self::Kernel),
+            "user" | "1" => Some(Self::UserLevel),
+            "mail" | "2" => Some(Self::MailSystem),
+            "daemon" | "3" => Some(Self::SystemDaemon),
+            "auth" | "security" | "4" => Some(Self::SecurityMessage),
+            "syslog" | "5" => Some(Self::SyslogdInternal),
+            "lpr" | "6" => Some(Self::LinePrinter),
+            "news" | "7" => Some(Self::NetworkNews),
+            "uucp" | "8" => Some(Self::Uucp),
+            "cron" | "clock" | "9" => Some(Self::ClockDaemon),
+            "authpriv" | "10" => Some(Self::SecurityAuthorization),
+            "ftp" | "11" => Some(Self::FtpDaemon),
+            "ntp" | "12" => Some(Self::Ntp),
+            "audit" | "13" => Some(Self::LogAudit),
+            "alert" | "14" => Some(Self::LogAlert),
+            "15" => Some(Self::SchedulingDaemon),
+            "local0" | "16" => Some(Self::Local0),
+            "local1" | "17" => Some(Self::Local1),
+            "local2" | "18" => Some(Self::Local2),
+            "local3" | "19" => Some(Self::Local3),
+            "local4" | "20" => Some(Self::Local4),
+            "local5" | "21" => Some(Self::Local5),
+            "local6" | "22" => Some(Self::Local6),
+            "local7" | "23" => Some(Self::Local7),
+            _ => None,
+        }
+    }
+
+    const fn numerical_code(self) -> u16 {
         match self {
             Self::Kernel => 0,
             Self::UserLevel => 1,
@@ -39,10 +73,12 @@ impl Facility {
             Self::NetworkNews => 7,
             Self::Uucp => 8,
             Self::ClockDaemon => 9,
+            Self::SecurityAuthorization => 10,
             Self::FtpDaemon => 11,
             Self::Ntp => 12,
             Self::LogAudit => 13,
             Self::LogAlert => 14,
+            Self::SchedulingDaemon => 15,
             Self::Local0 => 16,
             Self::Local1 => 17,
             Self::Local2 => 18,
@@ -55,7 +91,7 @@ impl Facility {
     }
 }
 
-#[allow(dead_code)]
+#[derive(Clone, Copy, PartialEq, Eq)]
 pub enum Severity {
     Emergency,
     Alert,
@@ -68,7 +104,41 @@ pub enum Severity {
 }
 
 impl Severity {
-    const fn numerical_code(&self) -> u16 {
+    pub const fn from_numerical_code(code: u64) -> Option<Self> {
+        match code {
+            0 => Some(Self::Emergency),
+            1 => Some(Self::Alert),
+            2 => Some(Self::Critical),
+            3 => Some(Self::Error),
+            4 => Some(Self::Warning),
+            5 => Some(Self::Notice),
+            6 => Some(Self::Informational),
+            7 => Some(Self::Debug),
+            _ => None,
+        }
+    }
+
+    /// Parses common log level names, case-insensitive, or numerical codes
+    pub fn from_name(name: &str) -> Option<Self> {
+        let name = name.trim().to_ascii_lowercase();
+        if let Ok(code) = name.parse() {
+            return Self::from_numerical_code(code);
+        }
+        match name.as_str() {
+            "emerg" | "emergency" => Some(Self::Emergency),
+            "alert" => Some(Self::Alert),
+            // Like the built-in severity rules, as application panics rarely affect the system
+            "crit" | "critical" | "fatal" | "panic" => Some(Self::Critical),
+            "err" | "error" => Some(Self::Error),
+            "warn" | "warning" => Some(Self::Warning),
+            "notice" => Some(Self::Notice),
+            "info" | "information" | "informational" => Some(Self::Informational),
+            "debug" | "trace" => Some(Self::Debug),
+            _ => None,
+        }
+    }
+
+    pub const fn numerical_code(self) -> u16 {
         match self {
             Self::Emergency => 0,
             Self::Alert => 1,
@@ -82,6 +152,71 @@ impl Severity {
     }
 }
 
+/// Structured data elements per RFC5424, not supported by RFC3164
+#[derive(Clone, Default)]
+pub struct StructuredData {
+    data: String,
+}
+
+fn append_sd_name(data: &mut String, name: &str) {
+    data.extend(
+        name.chars()
+            .filter(char::is_ascii_graphic)
+            .map(|c| if matches!(c, '=' | ']' | '"') { '_' } else { c })
+            .take(32),
+    );
+}
+
+impl StructuredData {
+    /// Adds an SD element, skipping it if it has no parameters
+    pub fn add<N, V>(&mut self, id: &str, params: impl IntoIterator<Item = (N, V)>)
+    where
+        N: AsRef<str>,
+        V: AsRef<str>,
+    {
+        let mut params = params.into_iter().peekable();
+        if params.peek().is_none() {
+            return;
+        }
+
+        self.data.push('[');
+        append_sd_name(&mut self.data, id);
+        for (name, value) in params {
+            self.data.push(' ');
+            append_sd_name(&mut self.data, name.as_ref());
+            self.data.push_str("=\"");
+            for c in value.as_ref().chars() {
+                match c {
+                    '"' | '\\' | ']' => {
+                        self.data.push('\\');
+                        self.data.push(c);
+                    }
+                    '\n' | '\r' => self.data.push(' '),
+                    c => self.data.push(c),
+                }
+            }
+            self.data.push('"');
+        }
+        self.data.push(']');
+    }
+
+    pub fn append(&mut self, other: &Self) {
+        self.data.push_str(&other.data);
+    }
+
+    pub const fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    fn as_str(&self) -> &str {
+        if self.data.is_empty() {
+            "-"
+        } else {
+            &self.data
+        }
+    }
+}
+
 pub enum Formatter {
     Rfc3164 {
         pri_offset: u16,
@@ -96,8 +231,19 @@ pub enum Formatter {
     },
 }
 
+/// Appends the message with its inner newlines, which the emitter replaces depending on the
+/// framing of the destination, followed by a single trailing newline
+fn append_msg(data: &mut Vec<u8>, msg: &[u8]) {
+    let end = msg
+        .iter()
+        .rposition(|b| !matches!(*b, b'\n' | b'\r'))
+        .map_or(0, |i| i + 1);
+    data.extend_from_slice(&msg[..end]);
+    data.push(b'\n');
+}
+
 impl Formatter {
-    pub fn rfc3164(facility: &Facility, hostname: &str, pid: Option<i64>) -> Self {
+    pub fn rfc3164(facility: Facility, hostname: &str, pid: Option<i64>) -> Self {
         let procid = pid.map_or_else(String::new, |p| format!("[{p}]"));
 
         Self::Rfc3164 {
@@ -108,7 +254,7 @@ impl Formatter {
     }
 
     pub fn rfc5424(
-        facility: &Facility,
+        facility: Facility,
         hostname: &str,
         pid: Option<i64>,
         msgid: Option<&str>,
@@ -121,28 +267,38 @@ impl Formatter {
 
         let procid = pid.map_or_else(|| "-".to_string(), |p| p.to_string());
 
-        let msgid = msgid.map_or("-", |msgid| {
-            if msgid.len() > 32 {
-                &msgid[0..32]
-            } else {
-                msgid
-            }
-        });
+        // MSGID is limited to 32 printable ASCII characters without spaces
+        let msgid: String = msgid
+            .unwrap_or_default()
+            .chars()
+            .filter(char::is_ascii_graphic)
+            .take(32)
+            .collect();
+        let msgid = if msgid.is_empty() {
+            "-".to_string()
+        } else {
+            msgid
+        };
 
         Self::Rfc5424 {
             pri_offset: facility.numerical_code() * 8,
             hostname: String::from(hostname),
             procid,
-            msgid: String::from(msgid),
+            msgid,
         }
     }
 
+    pub const fn supports_structured_data(&self) -> bool {
+        matches!(self, Self::Rfc5424 { .. })
+    }
+
     pub fn format<Tz: TimeZone>(
         &self,
         msg: &[u8],
         app_name: Option<&str>,
-        severity: &Severity,
+        severity: Severity,
         ts: &DateTime<Tz>,
+        structured_data: &StructuredData,
     ) -> Vec<u8>
     where
         Tz::Offset: std::fmt::Display,
@@ -160,8 +316,7 @@ impl Formatter {
                 let header = format!("<{pri}>{timestamp} {hostname} {app_name}{procid}: ");
 
                 let mut data = header.into_bytes();
-                data.extend(msg.iter().filter(|b| !matches!(**b, b'\n' | b'\r')));
-                data.push(b'\n');
+                append_msg(&mut data, msg);
                 data
             }
             Self::Rfc5424 {
@@ -180,12 +335,14 @@ impl Formatter {
                     }
                 });
 
-                let header =
-                    format!("<{pri}>1 {timestamp} {hostname} {app_name} {procid} {msgid} - ");
+                let structured_data = structured_data.as_str();
+
+                let header = format!(
+                    "<{pri}>1 {timestamp} {hostname} {app_name} {procid} {msgid} {structured_data} "
+                );
 
                 let mut data = header.into_bytes();
-                data.extend(msg.iter().filter(|b| !matches!(**b, b'\n' | b'\r')));
-                data.push(b'\n');
+                append_msg(&mut data, msg);
                 data
             }
         }
@@ -0,0 +1,90 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+use tokio_rustls::TlsConnector;
+
+fn open(path: &Path) -> Result<BufReader<File>, String> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|err| format!("Could not open \"{}\": {err}", path.display()))
+}
+
+fn read_certificates(path: &Path) -> Result<Vec<CertificateDer<'static>>, String> {
+    let certificates = rustls_pemfile::certs(&mut open(path)?)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| {
+            format!(
+                "Could not read certificates from \"{}\": {err}",
+                path.display()
+            )
+        })?;
+
+    if certificates.is_empty() {
+        return Err(format!("No certificates found in \"{}\"", path.display()));
+    }
+
+    Ok(certificates)
+}
+
+fn read_private_key(path: &Path) -> Result<PrivateKeyDer<'static>, String> {
+    match rustls_pemfile::private_key(&mut open(path)?) {
+        Ok(Some(key)) => Ok(key),
+        Ok(None) => Err(format!("No private key found in \"{}\"", path.display())),
+        Err(err) => Err(format!(
+            "Could not read private key from \"{}\": {err}",
+            path.display()
+        )),
+    }
+}
+
+fn root_store(ca_file: Option<&Path>) -> Result<RootCertStore, String> {
+    let mut roots = RootCertStore::empty();
+
+    if let Some(ca_file) = ca_file {
+        for certificate in read_certificates(ca_file)? {
+            roots.add(certificate).map_err(|err| {
+                format!("Invalid CA certificate in \"{}\": {err}", ca_file.display())
+            })?;
+        }
+        return Ok(roots);
+    }
+
+    let native = rustls_native_certs::load_native_certs();
+    for err in native.errors {
+        tracing::warn!("Could not load native CA certificate: {err}");
+    }
+    let (_, ignored) = roots.add_parsable_certificates(native.certs);
+    if ignored > 0 {
+        tracing::warn!("Ignored {ignored} unparsable native CA certificates");
+    }
+    if roots.is_empty() {
+        return Err("No CA certificates available".to_string());
+    }
+
+    Ok(roots)
+}
+
+/// Builds a TLS connector verifying servers against the given CA bundle (or the system
+/// certificate store if none is given), optionally authenticating with a client certificate.
+pub fn connector(
+    ca_file: Option<&Path>,
+    cert_file: Option<&Path>,
+    key_file: Option<&Path>,
+) -> Result<TlsConnector, String> {
+    let builder = ClientConfig::builder().with_root_certificates(root_store(ca_file)?);
+
+    let config = match (cert_file, key_file) {
+        (Some(cert_file), Some(key_file)) => builder
+            .with_client_auth_cert(read_certificates(cert_file)?, read_private_key(key_file)?)
+            .map_err(|err| format!("Invalid client certificate or key: {err}"))?,
+        (None, None) => builder.with_no_client_auth(),
+        _ => {
+            return Err("Client certificate and key have to be given together".to_string());
+        }
+    };
+
+    Ok(TlsConnector::from(Arc::new(config)))
+}